@@ -57,6 +57,9 @@ fn main() {
                         Ok(Unmap(p)) => {
                             println!("{:?}", p);
                         }
+                        Ok(Unknown { event, .. }) => {
+                            println!("Unknown event {:x}", event);
+                        }
                         Err(e) => {
                             println!("{:?}", e)
                         }
@@ -0,0 +1,76 @@
+use futures_core::Stream;
+use tokio::io::Interest;
+use tokio::io::unix::AsyncFd;
+
+use std::future::{self, Future};
+use std::io::Error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use Handle;
+use Message;
+
+/// A `Handle` driven by the tokio reactor.
+///
+/// Messages are read without blocking a runtime thread: `read_message()` waits for the userfaultfd
+/// object to become readable, and the `Stream` implementation yields every message as it arrives.
+#[derive(Debug)]
+pub struct AsyncHandle {
+    inner: AsyncFd<Handle>
+}
+
+impl AsyncHandle {
+    /// Register `handle` with the reactor of the current tokio runtime.
+    ///
+    /// The handle is switched to non-blocking mode if it was not created with the `non_block` option, so
+    /// that reading it never blocks a runtime thread.
+    ///
+    /// This function panics if called outside of a tokio runtime.
+    pub fn new(handle: Handle) -> Result<Self, Error> {
+        handle.set_non_blocking()?;
+        // Newer tokio releases deprecate this in favor of an unsafe constructor, but `Handle` owns its
+        // descriptor so the registration cannot outlive it
+        #[allow(deprecated)]
+        let inner = AsyncFd::with_interest(handle, Interest::READABLE)?;
+        Ok(AsyncHandle { inner })
+    }
+
+    /// The wrapped handle, for registering ranges and resolving the faults read from it.
+    pub fn get_ref(&self) -> &Handle {
+        self.inner.get_ref()
+    }
+
+    /// Deregister the handle from the reactor and return it.
+    pub fn into_inner(self) -> Handle {
+        self.inner.into_inner()
+    }
+
+    /// Wait for the next message and read it.
+    pub fn read_message<'a>(&'a self) -> impl Future<Output = Result<Message, Error>> + 'a {
+        future::poll_fn(move |cx| self.poll_read_message(cx))
+    }
+
+    /// Attempt to read the next message, registering the current task for wakeup if none is queued.
+    pub fn poll_read_message(&self, cx: &mut Context) -> Poll<Result<Message, Error>> {
+        loop {
+            let mut guard = match self.inner.poll_read_ready(cx) {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending
+            };
+            match guard.try_io(|inner| inner.get_ref().read_message()) {
+                Ok(result) => return Poll::Ready(result),
+                // Spurious wakeup, the readiness has been cleared so wait again
+                Err(_would_block) => continue
+            }
+        }
+    }
+}
+
+impl Stream for AsyncHandle {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.poll_read_message(cx).map(Some)
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+use ::raw_interface;
+use {Handle, Range, UffdError, WriteprotectMode};
+use REGISTER_WP;
+
+/// Tracks the pages written in a range without handling any fault.
+///
+/// The range is registered with `REGISTER_WP` and write-protected on a handle created with `wp_async`, so
+/// the kernel lifts the protection of a page by itself on the first write. The written pages are then
+/// found with the `PAGEMAP_SCAN` ioctl on `/proc/self/pagemap` `(since Linux 6.7)`, which can also
+/// protect them again in the same pass.
+///
+/// For anonymous memory that is not fully populated the handle should also be created with
+/// `wp_unpopulated`, otherwise the first write to an unpopulated page is not tracked.
+#[derive(Debug)]
+pub struct DirtyTracker {
+    handle: Handle,
+    pagemap: File,
+    range: Range,
+}
+
+unsafe impl Send for DirtyTracker {}
+unsafe impl Sync for DirtyTracker {}
+
+impl DirtyTracker {
+    /// Register and write-protect `range`, so that every page in it starts out clean.
+    pub fn new<T: Into<Range>>(handle: Handle, range: T) -> Result<Self, UffdError> {
+        let range = range.into();
+        let pagemap = File::open("/proc/self/pagemap")?;
+        handle.register(range, REGISTER_WP)?;
+        handle.write_protect(range, true, WriteprotectMode::empty())?;
+        Ok(Self { handle, pagemap, range })
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// The ranges of pages written since the tracker was created or the last call to `take_dirty()`.
+    pub fn dirty(&self) -> Result<Vec<Range>, UffdError> {
+        self.scan(0)
+    }
+
+    /// Like `dirty()`, and write-protect the returned pages again in the same pass, so that they count as
+    /// clean until they are written to again.
+    pub fn take_dirty(&self) -> Result<Vec<Range>, UffdError> {
+        self.scan(raw_interface::defines::PM_SCAN_WP_MATCHING as u64)
+    }
+
+    fn scan(&self, flags: u64) -> Result<Vec<Range>, UffdError> {
+        let written = raw_interface::defines::PAGE_IS_WRITTEN as u64;
+        let mut regions = vec![raw_interface::defines::page_region::default(); 64];
+        let mut ranges: Vec<Range> = Vec::new();
+        let end = self.range.start as u64 + self.range.len as u64;
+        let mut arg = raw_interface::defines::pm_scan_arg {
+            size: std::mem::size_of::<raw_interface::defines::pm_scan_arg>() as u64,
+            flags: flags | raw_interface::defines::PM_SCAN_CHECK_WPASYNC as u64,
+            start: self.range.start as u64,
+            end,
+            vec: regions.as_mut_ptr() as u64,
+            vec_len: regions.len() as u64,
+            category_mask: written,
+            return_mask: written,
+            ..Default::default()
+        };
+        loop {
+            let n = raw_interface::pagemap_scan(self.pagemap.as_raw_fd(), &mut arg)?;
+            for region in &regions[..n] {
+                let start = region.start as usize;
+                let len = (region.end - region.start) as usize;
+                // The kernel splits regions at the end of every call
+                match ranges.last_mut() {
+                    Some(last) if last.start as usize + last.len == start => last.len += len,
+                    _ => ranges.push(Range { start: start as *mut u8, len })
+                }
+            }
+            if arg.walk_end >= end {
+                return Ok(ranges);
+            }
+            arg.start = arg.walk_end;
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use libc;
+use std::fmt;
+use std::io::{self, Error, ErrorKind};
+
+use {Features, PageSize};
+
+/// Errors returned by the userfaultfd ioctls.
+///
+/// The documented failure causes of the ioctls get their own variants, so they can be matched on
+/// without inspecting errno values. Any other error is passed through as `Io`.
+#[derive(Debug)]
+pub enum UffdError {
+    /// `EBUSY`: a mapping in the range is registered with another userfaultfd object, or a page could not
+    /// be moved because it is pinned or shared.
+    Busy,
+    /// `EINVAL`: the range is not aligned to the page size, is empty or otherwise invalid, or an invalid
+    /// mode was requested.
+    InvalidArgument,
+    /// `ENOSPC` or `ESRCH`: the faulting process has exited.
+    ProcessExited,
+    /// `ENOENT`: the faulting process changed its virtual memory layout while the operation was in
+    /// progress.
+    MappingChanged,
+    /// `EEXIST`: a page is already present in the range, typically because another thread resolved the
+    /// same fault first.
+    PageExists,
+    /// `EAGAIN` without progress: a non-cooperative event such as a fork or an `mremap()` is waiting to
+    /// be read. The operation can be retried once the queued messages have been read and handled.
+    Again,
+    /// `ENOTTY`, `ENOSYS` or `EOPNOTSUPP`: the operation is not supported by the running kernel.
+    Unsupported,
+    /// The requested features are not supported by the running kernel.
+    FeaturesUnsupported(Features),
+    /// The `user_mode_only` option is not supported by the running kernel.
+    UserModeOnlyUnsupported,
+    /// A range was rejected before being passed to the kernel.
+    InvalidRange(RangeError),
+    /// An ioctl returned a value the crate does not know how to interpret.
+    UnexpectedReturn { ioctl: &'static str, value: i64 },
+    /// Any other error.
+    Io(Error)
+}
+
+impl UffdError {
+    pub(crate) fn from_ioctl(e: Error) -> UffdError {
+        match e.raw_os_error() {
+            Some(libc::EBUSY) => UffdError::Busy,
+            Some(libc::EINVAL) => UffdError::InvalidArgument,
+            Some(libc::ENOSPC) | Some(libc::ESRCH) => UffdError::ProcessExited,
+            Some(libc::ENOENT) => UffdError::MappingChanged,
+            Some(libc::EEXIST) => UffdError::PageExists,
+            Some(libc::EAGAIN) => UffdError::Again,
+            Some(libc::ENOTTY) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => UffdError::Unsupported,
+            _ => UffdError::Io(e)
+        }
+    }
+}
+
+impl fmt::Display for UffdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UffdError::Busy => write!(f, "range is busy or registered with another userfaultfd object"),
+            UffdError::InvalidArgument => write!(f, "invalid range or mode"),
+            UffdError::ProcessExited => write!(f, "the faulting process has exited"),
+            UffdError::MappingChanged => write!(f, "the faulting process changed its memory layout"),
+            UffdError::PageExists => write!(f, "page already present"),
+            UffdError::Again => write!(f, "an event must be read before the operation can be retried"),
+            UffdError::Unsupported => write!(f, "operation not supported by the kernel"),
+            UffdError::FeaturesUnsupported(features) =>
+                write!(f, "userfaultfd features not supported by the kernel: {:?}", features),
+            UffdError::UserModeOnlyUnsupported =>
+                write!(f, "UFFD_USER_MODE_ONLY is not supported by the kernel (requires Linux 5.11)"),
+            UffdError::InvalidRange(ref e) => write!(f, "invalid range: {}", e),
+            UffdError::UnexpectedReturn { ioctl, value } =>
+                write!(f, "unexpected return value from {} ioctl: {}", ioctl, value),
+            UffdError::Io(ref e) => e.fmt(f)
+        }
+    }
+}
+
+impl std::error::Error for UffdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            UffdError::Io(ref e) => Some(e),
+            UffdError::InvalidRange(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<Error> for UffdError {
+    fn from(e: Error) -> UffdError {
+        UffdError::Io(e)
+    }
+}
+
+impl From<RangeError> for UffdError {
+    fn from(e: RangeError) -> UffdError {
+        UffdError::InvalidRange(e)
+    }
+}
+
+impl From<UffdError> for Error {
+    fn from(e: UffdError) -> Error {
+        let errno = match e {
+            UffdError::Busy => libc::EBUSY,
+            UffdError::InvalidArgument => libc::EINVAL,
+            UffdError::ProcessExited => libc::ESRCH,
+            UffdError::MappingChanged => libc::ENOENT,
+            UffdError::PageExists => libc::EEXIST,
+            UffdError::Again => libc::EAGAIN,
+            UffdError::Unsupported => libc::ENOTTY,
+            UffdError::Io(e) => return e,
+            e @ UffdError::UnexpectedReturn { .. } => return io::Error::other(e),
+            e => return io::Error::new(ErrorKind::InvalidInput, e)
+        };
+        Error::from_raw_os_error(errno)
+    }
+}
+
+/// Why a `Range` was rejected by one of its checked constructors or before an ioctl.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeError {
+    /// The range has a length of zero.
+    Empty,
+    /// The start address is not a multiple of the page size.
+    UnalignedStart { start: usize, page_size: PageSize },
+    /// The length is not a multiple of the page size.
+    UnalignedLen { len: usize, page_size: PageSize },
+    /// The range reaches past the end of the address space.
+    Overflow { start: usize, len: usize },
+    /// An offset is past the end of the range.
+    OutOfBounds { offset: usize, len: usize },
+}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RangeError::Empty => write!(f, "the range is empty"),
+            RangeError::UnalignedStart { start, page_size } =>
+                write!(f, "start address {:#x} is not aligned to the {} page size", start, page_size),
+            RangeError::UnalignedLen { len, page_size } =>
+                write!(f, "length {:#x} is not a multiple of the {} page size", len, page_size),
+            RangeError::Overflow { start, len } =>
+                write!(f, "range of {:#x} bytes at {:#x} wraps around the address space", len, start),
+            RangeError::OutOfBounds { offset, len } =>
+                write!(f, "offset {:#x} is past the end of a range of {:#x} bytes", offset, len)
+        }
+    }
+}
+
+impl std::error::Error for RangeError {}
@@ -0,0 +1,162 @@
+use libc;
+use std::fmt;
+use std::fs::File;
+use std::io::Error;
+use std::os::unix::fs::FileExt;
+use std::ptr;
+use std::slice;
+
+use {Builder, Handle, Monitor, MonitorBuilder, PageSize, PageSource, Range, UffdError};
+use REGISTER_MISSING;
+
+type Transform = dyn Fn(u64, &mut [u8]) -> Result<(), Error> + Send + Sync;
+
+/// A `PageSource` reading pages from a file with `pread(2)`.
+///
+/// The address `base` maps to the file at `offset`, and every other address maps to the file at the same
+/// distance from `offset`. Pages reaching past the end of the file are zero-filled, like a file `mmap`.
+pub struct FilePageSource {
+    file: File,
+    offset: u64,
+    base: usize,
+    transform: Option<Box<Transform>>,
+}
+
+impl FilePageSource {
+    /// Serve `file` starting at `offset`. The base address defaults to null, see `base()`.
+    pub fn new(file: File, offset: u64) -> Self {
+        Self {
+            file,
+            offset,
+            base: 0,
+            transform: None,
+        }
+    }
+
+    /// Set the address at which `offset` is mapped. `LazyFileRegion` sets this itself.
+    pub fn base(mut self, base: *mut u8) -> Self {
+        self.base = base as usize;
+        self
+    }
+
+    /// Run `transform` on every page after it has been read and before it is installed, for example to
+    /// decrypt, decompress or validate it. It is passed the file offset the page was read from. An error
+    /// is handled like a failed read, see `PageSource::fill()`.
+    pub fn transform<F>(mut self, transform: F) -> Self
+        where F: Fn(u64, &mut [u8]) -> Result<(), Error> + Send + Sync + 'static
+    {
+        self.transform = Some(Box::new(transform));
+        self
+    }
+}
+
+impl PageSource for FilePageSource {
+    fn fill(&self, addr: usize, page: &mut [u8]) -> Result<(), Error> {
+        let offset = self.offset + (addr - self.base) as u64;
+        let mut read = 0;
+        while read < page.len() {
+            match self.file.read_at(&mut page[read..], offset + read as u64)? {
+                0 => break,
+                n => read += n
+            }
+        }
+        for b in &mut page[read..] {
+            *b = 0;
+        }
+        match self.transform {
+            Some(ref transform) => transform(offset, page),
+            None => Ok(())
+        }
+    }
+}
+
+impl fmt::Debug for FilePageSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FilePageSource")
+            .field("file", &self.file)
+            .field("offset", &self.offset)
+            .field("base", &(self.base as *const u8))
+            .field("transform", &self.transform.is_some())
+            .finish()
+    }
+}
+
+/// Private anonymous memory whose pages are read from a file on first touch.
+///
+/// Unlike a file `mmap`, pages go through the source's transform before they become visible, and writes
+/// are never carried back to the file. The faults are served by a `Monitor` owned by the region; the
+/// memory is unregistered and unmapped when the region is dropped.
+#[derive(Debug)]
+pub struct LazyFileRegion {
+    monitor: Monitor,
+    start: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for LazyFileRegion {}
+unsafe impl Sync for LazyFileRegion {}
+
+impl LazyFileRegion {
+    /// Reserve `len` bytes, rounded up to the page size, and serve them from `source` through a new
+    /// non-blocking userfaultfd.
+    pub fn new(source: FilePageSource, len: usize) -> Result<Self, UffdError> {
+        let (handle, _) = Builder::new().close_on_exec(true).non_block(true).create()?;
+        Self::with_handle(handle, source, len)
+    }
+
+    /// Like `new()`, but with a handle created by the caller. The handle must be non-blocking.
+    pub fn with_handle(handle: Handle, source: FilePageSource, len: usize) -> Result<Self, UffdError> {
+        let page_size = PageSize::system();
+        let len = page_size.align_up(len);
+        let start = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE, -1, 0)
+        };
+        if start == libc::MAP_FAILED {
+            return Err(UffdError::Io(Error::last_os_error()));
+        }
+        let start = start as *mut u8;
+        let unmap = |e: UffdError| {
+            unsafe { libc::munmap(start as *mut libc::c_void, len); }
+            e
+        };
+        handle.register(Range { start, len }, REGISTER_MISSING).map_err(&unmap)?;
+        let monitor = MonitorBuilder::new()
+            .page_size(page_size)
+            .spawn(handle, source.base(start))
+            .map_err(|e| unmap(e.into()))?;
+        Ok(Self { monitor, start, len })
+    }
+
+    /// The handle serving the region.
+    pub fn handle(&self) -> &Handle {
+        self.monitor.handle()
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.start, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.start, self.len) }
+    }
+}
+
+impl Drop for LazyFileRegion {
+    fn drop(&mut self) {
+        let _ = self.monitor.handle().unregister(Range { start: self.start, len: self.len });
+        unsafe { libc::munmap(self.start as *mut libc::c_void, self.len); }
+    }
+}
@@ -2,17 +2,59 @@
 extern crate sc;
 #[macro_use]
 extern crate bitflags;
+extern crate libc;
 #[cfg(feature = "mio")]
 extern crate mio;
+#[cfg(feature = "mio08")]
+extern crate mio08;
+#[cfg(feature = "mio1")]
+extern crate mio1;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tokio")]
+extern crate futures_core;
 
 #[cfg(feature = "mio")]
 use mio::unix::EventedFd;
 
 use std::os::unix::io::{AsRawFd,FromRawFd,IntoRawFd,RawFd};
-use std::io::Error;
+use std::io::{Error,ErrorKind};
 use std::io;
 
+macro_rules! builder_methods {
+    ( $($arg:ident : $type:ty),+) => {
+        $(pub fn $arg(mut self, value: $type) -> Self {
+            self.$arg = value;
+            self
+        })+
+    }
+}
+
 mod raw_interface;
+mod error;
+mod page_size;
+mod sigbus;
+mod monitor;
+mod file;
+mod snapshot;
+mod dirty;
+mod region;
+mod shutdown;
+pub mod postcopy;
+pub mod transfer;
+#[cfg(feature = "tokio")]
+mod async_handle;
+
+pub use error::{RangeError, UffdError};
+pub use page_size::PageSize;
+pub use sigbus::{handle_sigbus, SigbusRange, MAX_SIGBUS_RANGES};
+pub use monitor::{Monitor, MonitorBuilder, PageSource};
+pub use file::{FilePageSource, LazyFileRegion};
+pub use snapshot::Snapshot;
+pub use dirty::DirtyTracker;
+pub use region::{Backing, Region};
+#[cfg(feature = "tokio")]
+pub use async_handle::AsyncHandle;
 
 #[cfg(test)]
 mod tests;
@@ -21,20 +63,57 @@ mod tests;
 pub struct Builder {
     close_on_exec: bool,
     non_block: bool,
+    user_mode_only: bool,
     event_fork: bool,
     event_remap: bool,
     event_remove: bool,
     event_unmap: bool,
     hugetlbfs: bool,
     shmem: bool,
+    minor_hugetlbfs: bool,
+    minor_shmem: bool,
+    move_pages: bool,
+    poison: bool,
+    wp_async: bool,
+    wp_unpopulated: bool,
+    thread_id: bool,
+    sigbus: bool,
+    features: Features,
+    backend: Backend,
 }
 
-macro_rules! builder_methods {
-    ( $($arg:ident : $type:ty),+) => {
-        $(pub fn $arg(mut self, value: $type) -> Self {
-            self.$arg = value;
-            self
-        })+
+/// How the userfaultfd object is obtained from the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Use the `userfaultfd(2)` syscall, and fall back to `/dev/userfaultfd` if the syscall is denied or
+    /// unavailable. This is the default.
+    Auto,
+    /// Only use the `userfaultfd(2)` syscall. Unprivileged callers need `vm.unprivileged_userfaultfd=1`.
+    Syscall,
+    /// Only use the `USERFAULTFD_IOC_NEW` ioctl on `/dev/userfaultfd` `(since Linux 6.1)`. Access is
+    /// governed by the permissions of the device node rather than by `vm.unprivileged_userfaultfd`.
+    Device,
+}
+
+impl Backend {
+    fn open(self, flags: usize) -> Result<RawFd, Error> {
+        match self {
+            Backend::Syscall => raw_interface::userfaultfd(flags),
+            Backend::Device => raw_interface::userfaultfd_dev(flags),
+            Backend::Auto => match raw_interface::userfaultfd(flags) {
+                Err(e) => {
+                    if e.kind() != ErrorKind::PermissionDenied && e.kind() != ErrorKind::Unsupported {
+                        return Err(e);
+                    }
+                    match raw_interface::userfaultfd_dev(flags) {
+                        // Report the syscall failure on kernels without the device node
+                        Err(ref dev_e) if dev_e.kind() == ErrorKind::NotFound => Err(e),
+                        x => x
+                    }
+                }
+                x => x
+            }
+        }
     }
 }
 
@@ -46,34 +125,88 @@ impl Builder {
     builder_methods!{
         close_on_exec: bool,
         non_block: bool,
+        user_mode_only: bool,
         event_fork: bool,
         event_remap: bool,
         event_remove: bool,
         event_unmap: bool,
         hugetlbfs: bool,
-        shmem: bool
+        shmem: bool,
+        minor_hugetlbfs: bool,
+        minor_shmem: bool,
+        move_pages: bool,
+        poison: bool,
+        wp_async: bool,
+        wp_unpopulated: bool,
+        thread_id: bool,
+        sigbus: bool,
+        features: Features,
+        backend: Backend
     }
 
-    pub fn create(self) -> Result<(Handle, u64), Error> {
+    /// Create the userfaultfd object and enable the requested features.
+    ///
+    /// With `sigbus` `(since Linux 4.14)` faults on registered ranges raise `SIGBUS` on the faulting thread
+    /// instead of being queued as messages; see `handle_sigbus()` for resolving them in-thread.
+    ///
+    /// With `user_mode_only` the object only handles faults raised from user mode `(since Linux 5.11)`.
+    /// Unprivileged processes need it when `vm.unprivileged_userfaultfd=0` and they lack `CAP_SYS_PTRACE`.
+    ///
+    /// With `wp_async` `(since Linux 6.7)` writes to write-protected ranges are resolved by the kernel,
+    /// which lifts the protection without queueing a message; the written pages can then be found with
+    /// `DirtyTracker`. With `wp_unpopulated` `(since Linux 6.4)` write-protecting anonymous memory also
+    /// covers the pages that are not populated yet.
+    ///
+    /// The boolean feature options are combined with the set passed to `features()`. If the kernel does
+    /// not support some of the requested features, `UffdError::FeaturesUnsupported` naming them is
+    /// returned; use `probe()` to find out what the running kernel supports beforehand.
+    pub fn create(self) -> Result<(Handle, u64), UffdError> {
         let flags = 
             if self.close_on_exec { raw_interface::defines::O_CLOEXEC }  else { 0 } |
-            if self.non_block     { raw_interface::defines::O_NONBLOCK } else { 0 };
-        let raw_fd = raw_interface::userfaultfd(flags as usize);
+            if self.non_block     { raw_interface::defines::O_NONBLOCK } else { 0 } |
+            if self.user_mode_only { raw_interface::defines::UFFD_USER_MODE_ONLY } else { 0 };
+        let handle = match self.backend.open(flags as usize) {
+            Ok(fd) => Handle(fd),
+            Err(ref e) if e.kind() == ErrorKind::InvalidInput && self.user_mode_only => {
+                return Err(UffdError::UserModeOnlyUnsupported);
+            }
+            Err(e) => return Err(UffdError::Io(e))
+        };
 
-        let features =
-              if self.event_fork   { raw_interface::defines::UFFD_FEATURE_EVENT_FORK        } else { 0 }
-            | if self.event_remap  { raw_interface::defines::UFFD_FEATURE_EVENT_REMAP       } else { 0 }
-            | if self.event_remove { raw_interface::defines::UFFD_FEATURE_EVENT_REMOVE      } else { 0 }
-            | if self.event_unmap  { raw_interface::defines::UFFD_FEATURE_EVENT_UNMAP       } else { 0 }
-            | if self.hugetlbfs    { raw_interface::defines::UFFD_FEATURE_MISSING_HUGETLBFS } else { 0 }
-            | if self.shmem        { raw_interface::defines::UFFD_FEATURE_MISSING_SHMEM     } else { 0 };
+        let features = self.features
+            | if self.event_fork      { FEATURE_EVENT_FORK        } else { Features::empty() }
+            | if self.event_remap     { FEATURE_EVENT_REMAP       } else { Features::empty() }
+            | if self.event_remove    { FEATURE_EVENT_REMOVE      } else { Features::empty() }
+            | if self.event_unmap     { FEATURE_EVENT_UNMAP       } else { Features::empty() }
+            | if self.hugetlbfs       { FEATURE_MISSING_HUGETLBFS } else { Features::empty() }
+            | if self.shmem           { FEATURE_MISSING_SHMEM     } else { Features::empty() }
+            | if self.minor_hugetlbfs { FEATURE_MINOR_HUGETLBFS   } else { Features::empty() }
+            | if self.minor_shmem     { FEATURE_MINOR_SHMEM       } else { Features::empty() }
+            | if self.move_pages      { FEATURE_MOVE              } else { Features::empty() }
+            | if self.poison          { FEATURE_POISON            } else { Features::empty() }
+            | if self.wp_async        { FEATURE_WP_ASYNC          } else { Features::empty() }
+            | if self.wp_unpopulated  { FEATURE_WP_UNPOPULATED    } else { Features::empty() }
+            | if self.thread_id       { FEATURE_THREAD_ID         } else { Features::empty() }
+            | if self.sigbus          { FEATURE_SIGBUS            } else { Features::empty() };
 
         let mut req = raw_interface::defines::uffdio_api {
             api: raw_interface::defines::UFFD_API,
-            features: features as u64,
+            features: features.bits(),
             ioctls: 0
         };
-        raw_interface::uffdio_api(raw_fd, &mut req).map(|()|(Handle(raw_fd), req.ioctls))
+        match raw_interface::uffdio_api(handle.0, &mut req) {
+            Ok(()) => Ok((handle, req.ioctls)),
+            Err(UffdError::InvalidArgument) => {
+                // The kernel answers EINVAL for any unknown feature bit, find out which ones it was
+                match probe_with(self.backend, (flags | raw_interface::defines::O_CLOEXEC) as usize) {
+                    Ok((supported, _)) if !supported.contains(features) => {
+                        Err(UffdError::FeaturesUnsupported(features - supported))
+                    }
+                    _ => Err(UffdError::InvalidArgument)
+                }
+            }
+            Err(e) => Err(e)
+        }
     }
 }
 
@@ -82,19 +215,53 @@ impl Default for Builder {
         Self {
             close_on_exec: false,
             non_block: false,
+            user_mode_only: false,
             event_fork: false,
             event_remap: false,
             event_remove: false,
             event_unmap: false,
             hugetlbfs: false,
             shmem: false,
+            minor_hugetlbfs: false,
+            minor_shmem: false,
+            move_pages: false,
+            poison: false,
+            wp_async: false,
+            wp_unpopulated: false,
+            thread_id: false,
+            sigbus: false,
+            features: Features::empty(),
+            backend: Backend::Auto,
         }
     }
 }
 
+/// Query the features and ioctls supported by the running kernel.
+///
+/// This performs the first half of the `UFFDIO_API` handshake on a temporary userfaultfd object: the
+/// kernel is asked for API version `UFFD_API` with no features, and answers with every feature it knows
+/// about. Since the handshake can only be done once per object, the returned `Features` are then enabled
+/// on a fresh object through `Builder::features()`.
+pub fn probe() -> Result<(Features, Ioctls), Error> {
+    probe_with(Backend::Auto, raw_interface::defines::O_CLOEXEC as usize)
+}
+
+/// `probe()` on an object opened the way a `Builder` would
+fn probe_with(backend: Backend, flags: usize) -> Result<(Features, Ioctls), Error> {
+    let handle = Handle(backend.open(flags)?);
+    let mut req = raw_interface::defines::uffdio_api {
+        api: raw_interface::defines::UFFD_API,
+        features: 0,
+        ioctls: 0
+    };
+    raw_interface::uffdio_api(handle.0, &mut req)?;
+    Ok((Features::from_bits_truncate(req.features), Ioctls::from_bits_truncate(req.ioctls)))
+}
+
 #[derive(Debug)]
 pub struct Handle(RawFd);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Range {
     pub start: *mut u8,
     pub len: usize
@@ -109,25 +276,162 @@ impl<'a,T> From<&'a mut [T]> for Range {
     }
 }
 
-impl<'a> From<&'a PagefaultMessage> for Range {
-    fn from(msg: &'a PagefaultMessage) -> Self {
+impl Range {
+    /// A range of `len` bytes at `start`, both of which must be multiples of `page_size`.
+    pub fn new_aligned(start: *mut u8, len: usize, page_size: PageSize) -> Result<Self, RangeError> {
+        let range = Self { start, len };
+        range.check(page_size)?;
+        Ok(range)
+    }
+
+    /// The smallest range of whole pages of `page_size` containing the `len` bytes at `start`.
+    pub fn covering(start: *mut u8, len: usize, page_size: PageSize) -> Result<Self, RangeError> {
+        if len == 0 {
+            return Err(RangeError::Empty);
+        }
+        let end = (start as usize).checked_add(len)
+            .and_then(|end| end.checked_add(page_size.bytes() - 1))
+            .ok_or(RangeError::Overflow { start: start as usize, len })?;
+        let aligned = page_size.align_down(start as usize);
+        Ok(Self { start: aligned as *mut u8, len: page_size.align_down(end) - aligned })
+    }
+
+    /// Check that the range is not empty, does not wrap around and is aligned to `page_size`, which the
+    /// kernel otherwise reports as a bare `EINVAL`.
+    pub fn check(&self, page_size: PageSize) -> Result<(), RangeError> {
+        let start = self.start as usize;
+        if self.len == 0 {
+            Err(RangeError::Empty)
+        } else if let Err(e) = self.end() {
+            Err(e)
+        } else if !page_size.is_aligned(start) {
+            Err(RangeError::UnalignedStart { start, page_size })
+        } else if !page_size.is_aligned(self.len) {
+            Err(RangeError::UnalignedLen { len: self.len, page_size })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The address one past the end of the range, or `RangeError::Overflow` if the range wraps around
+    /// the address space.
+    pub fn end(&self) -> Result<*mut u8, RangeError> {
+        (self.start as usize).checked_add(self.len)
+            .map(|end| end as *mut u8)
+            .ok_or(RangeError::Overflow { start: self.start as usize, len: self.len })
+    }
+
+    /// Split the range into the `offset` bytes at its start and the rest.
+    pub fn split_at(&self, offset: usize) -> Result<(Self, Self), RangeError> {
+        if offset > self.len {
+            return Err(RangeError::OutOfBounds { offset, len: self.len });
+        }
+        let head = Self { start: self.start, len: offset };
+        let tail = Self { start: (self.start as usize + offset) as *mut u8, len: self.len - offset };
+        Ok((head, tail))
+    }
+
+    /// The part of the range that is also in `other`, if any. Ranges wrapping around the address space
+    /// have no intersection.
+    pub fn intersect(&self, other: &Range) -> Option<Self> {
+        let start = (self.start as usize).max(other.start as usize);
+        let end = (self.end().ok()? as usize).min(other.end().ok()? as usize);
+        if start < end {
+            Some(Self { start: start as *mut u8, len: end - start })
+        } else {
+            None
+        }
+    }
+
+    /// Iterate over the pages of `page_size` overlapping the range, each as a range of its own.
+    ///
+    /// `RangeError::Overflow` is returned if the range, rounded up to whole pages, wraps around the
+    /// address space.
+    pub fn pages(&self, page_size: PageSize) -> Result<Pages, RangeError> {
+        let end = (self.end()? as usize).checked_add(page_size.bytes() - 1)
+            .map(|end| page_size.align_down(end))
+            .ok_or(RangeError::Overflow { start: self.start as usize, len: self.len })?;
+        Ok(Pages {
+            next: if self.len == 0 { end } else { page_size.align_down(self.start as usize) },
+            end,
+            page_size,
+        })
+    }
+
+    /// The page containing the faulting address of `msg`, in a mapping with pages of `page_size`.
+    ///
+    /// This is the system page size for anonymous and shmem mappings, and the huge page size for
+    /// hugetlbfs mappings, which `PageSize::of()` can look up.
+    pub fn from_fault(msg: &PagefaultMessage, page_size: PageSize) -> Self {
         Self {
-            start: msg.address as *mut u8,
-            len: 4096
+            start: page_size.align_down(msg.address as usize) as *mut u8,
+            len: page_size.bytes()
         }
     }
 }
 
+/// Iterator over the pages of a range, returned by `Range::pages()`.
+#[derive(Debug, Clone)]
+pub struct Pages {
+    next: usize,
+    end: usize,
+    page_size: PageSize,
+}
+
+impl Iterator for Pages {
+    type Item = Range;
+
+    fn next(&mut self) -> Option<Range> {
+        if self.next >= self.end {
+            return None;
+        }
+        let page = Range { start: self.next as *mut u8, len: self.page_size.bytes() };
+        self.next += self.page_size.bytes();
+        Some(page)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = (self.end - self.next) / self.page_size.bytes();
+        (n, Some(n))
+    }
+}
+
+impl ExactSizeIterator for Pages {}
+
+bitflags! {
+    pub struct Features: u64 {
+        const FEATURE_PAGEFAULT_FLAG_WP = raw_interface::defines::UFFD_FEATURE_PAGEFAULT_FLAG_WP as u64;
+        const FEATURE_EVENT_FORK = raw_interface::defines::UFFD_FEATURE_EVENT_FORK as u64;
+        const FEATURE_EVENT_REMAP = raw_interface::defines::UFFD_FEATURE_EVENT_REMAP as u64;
+        const FEATURE_EVENT_REMOVE = raw_interface::defines::UFFD_FEATURE_EVENT_REMOVE as u64;
+        const FEATURE_MISSING_HUGETLBFS = raw_interface::defines::UFFD_FEATURE_MISSING_HUGETLBFS as u64;
+        const FEATURE_MISSING_SHMEM = raw_interface::defines::UFFD_FEATURE_MISSING_SHMEM as u64;
+        const FEATURE_EVENT_UNMAP = raw_interface::defines::UFFD_FEATURE_EVENT_UNMAP as u64;
+        const FEATURE_SIGBUS = raw_interface::defines::UFFD_FEATURE_SIGBUS as u64;
+        const FEATURE_THREAD_ID = raw_interface::defines::UFFD_FEATURE_THREAD_ID as u64;
+        const FEATURE_MINOR_HUGETLBFS = raw_interface::defines::UFFD_FEATURE_MINOR_HUGETLBFS as u64;
+        const FEATURE_MINOR_SHMEM = raw_interface::defines::UFFD_FEATURE_MINOR_SHMEM as u64;
+        const FEATURE_EXACT_ADDRESS = raw_interface::defines::UFFD_FEATURE_EXACT_ADDRESS as u64;
+        const FEATURE_WP_HUGETLBFS_SHMEM = raw_interface::defines::UFFD_FEATURE_WP_HUGETLBFS_SHMEM as u64;
+        const FEATURE_WP_UNPOPULATED = raw_interface::defines::UFFD_FEATURE_WP_UNPOPULATED as u64;
+        const FEATURE_POISON = raw_interface::defines::UFFD_FEATURE_POISON as u64;
+        const FEATURE_WP_ASYNC = raw_interface::defines::UFFD_FEATURE_WP_ASYNC as u64;
+        const FEATURE_MOVE = raw_interface::defines::UFFD_FEATURE_MOVE as u64;
+    }
+}
+
 bitflags! {
     pub struct RegisterMode: u64 {
         const REGISTER_MISSING = raw_interface::defines::UFFDIO_REGISTER_MODE_MISSING;
         const REGISTER_WP = raw_interface::defines::UFFDIO_REGISTER_MODE_WP;
+        const REGISTER_MINOR = raw_interface::defines::UFFDIO_REGISTER_MODE_MINOR;
     }
 }
 
 bitflags! {
     pub struct CopyMode: u64 {
         const COPY_DONTWAKE = raw_interface::defines::UFFDIO_COPY_MODE_DONTWAKE;
+        const COPY_WP = raw_interface::defines::UFFDIO_COPY_MODE_WP;
     }
 }
 
@@ -137,6 +441,39 @@ bitflags! {
     }
 }
 
+bitflags! {
+    pub struct WriteprotectMode: u64 {
+        const WRITEPROTECT_DONTWAKE = raw_interface::defines::UFFDIO_WRITEPROTECT_MODE_DONTWAKE;
+    }
+}
+
+bitflags! {
+    pub struct ContinueMode: u64 {
+        const CONTINUE_DONTWAKE = raw_interface::defines::UFFDIO_CONTINUE_MODE_DONTWAKE;
+    }
+}
+
+bitflags! {
+    pub struct MoveMode: u64 {
+        const MOVE_DONTWAKE = raw_interface::defines::UFFDIO_MOVE_MODE_DONTWAKE;
+        const MOVE_ALLOW_SRC_HOLES = raw_interface::defines::UFFDIO_MOVE_MODE_ALLOW_SRC_HOLES;
+    }
+}
+
+bitflags! {
+    pub struct PoisonMode: u64 {
+        const POISON_DONTWAKE = raw_interface::defines::UFFDIO_POISON_MODE_DONTWAKE;
+    }
+}
+
+bitflags! {
+    pub struct PagefaultFlags: u64 {
+        const PAGEFAULT_FLAG_WRITE = raw_interface::defines::UFFD_PAGEFAULT_FLAG_WRITE as u64;
+        const PAGEFAULT_FLAG_WP = raw_interface::defines::UFFD_PAGEFAULT_FLAG_WP as u64;
+        const PAGEFAULT_FLAG_MINOR = raw_interface::defines::UFFD_PAGEFAULT_FLAG_MINOR as u64;
+    }
+}
+
 bitflags! {
     pub struct Ioctls: u64 {
         const IOCTL_API = 1 << raw_interface::defines::_UFFDIO_API;
@@ -147,6 +484,10 @@ bitflags! {
         const IOCTL_COPY = 1 << raw_interface::defines::_UFFDIO_COPY;
         const IOCTL_RANGE_IOCTLS = raw_interface::defines::UFFD_API_RANGE_IOCTLS;
         const IOCTL_ZEROPAGE = 1 << raw_interface::defines::_UFFDIO_ZEROPAGE;
+        const IOCTL_WRITEPROTECT = 1 << raw_interface::defines::_UFFDIO_WRITEPROTECT;
+        const IOCTL_CONTINUE = 1 << raw_interface::defines::_UFFDIO_CONTINUE;
+        const IOCTL_MOVE = 1 << raw_interface::defines::_UFFDIO_MOVE;
+        const IOCTL_POISON = 1 << raw_interface::defines::_UFFDIO_POISON;
         const IOCTL_RANGE_IOCTLS_BASIC = raw_interface::defines::UFFD_API_RANGE_IOCTLS_BASIC;
     }
 }
@@ -172,6 +513,18 @@ impl std::fmt::Display for Ioctls {
         if self.contains(IOCTL_ZEROPAGE) {
             write!(f, "IOCTL_ZEROPAGE")?;
         }
+        if self.contains(IOCTL_WRITEPROTECT) {
+            write!(f, "IOCTL_WRITEPROTECT")?;
+        }
+        if self.contains(IOCTL_CONTINUE) {
+            write!(f, "IOCTL_CONTINUE")?;
+        }
+        if self.contains(IOCTL_MOVE) {
+            write!(f, "IOCTL_MOVE")?;
+        }
+        if self.contains(IOCTL_POISON) {
+            write!(f, "IOCTL_POISON")?;
+        }
         write!(f, "]")
     }
 }
@@ -193,10 +546,15 @@ impl Handle {
     /// * `REGISTER_MISSING`
     ///        Track page faults on missing pages.
     /// 
-    /// * `REGISTER_WP`
-    ///        Track page faults on write-protected pages.
+    /// * `REGISTER_WP` `(since Linux 5.7)`
+    ///        Track page faults on write-protected pages. Pages are write-protected and un-protected with
+    ///        `write_protect()`.
     /// 
-    /// Currently, the only supported mode is `REGISTER_MISSING`.
+    /// * `REGISTER_MINOR` `(since Linux 5.13)`
+    ///        Track minor page faults: faults on pages that are present in the page cache but not yet mapped
+    ///        into the faulting range. Only shmem and hugetlbfs ranges can be registered in this mode, and the
+    ///        corresponding `minor_shmem` or `minor_hugetlbfs` option must be enabled on the `Builder`.
+    ///        Minor faults are resolved with `continue_range()`.
     /// 
     /// If the operation is successful, the kernel returns which operations are available for the specified
     /// range.
@@ -215,9 +573,20 @@ impl Handle {
     ///        these fields are otherwise invalid.
     /// 
     /// * `EINVAL` There as an incompatible mapping in the specified address range.
-
-    pub fn register<T: Into<Range>>(&self, range: T, mode: RegisterMode) -> Result<Ioctls, Error> {
-        raw_interface::uffdio_register(self.0, mode.bits(), range.into().into()).map(|x|Ioctls::from_bits_truncate(x))
+    ///
+    /// Ranges that are empty or not aligned to the system page size are rejected with `InvalidRange`
+    /// before the ioctl is issued.
+    pub fn register<T: Into<Range>>(&self, range: T, mode: RegisterMode) -> Result<Ioctls, UffdError> {
+        let range = range.into();
+        range.check(PageSize::system())?;
+        raw_interface::uffdio_register(self.0, mode.bits(), range.into()).map(|x|Ioctls::from_bits_truncate(x))
+    }
+    /// Map `len` bytes of memory from `backing`, rounded up to its page size, and register them with `mode`.
+    ///
+    /// Unlike ranges mapped by the caller and passed to `register()`, the returned `Region` borrows the
+    /// handle and is unregistered and unmapped when it is dropped.
+    pub fn create_region(&self, len: usize, backing: Backing, mode: RegisterMode) -> Result<Region<'_>, UffdError> {
+        Region::new(self, len, backing, mode)
     }
     /// `(Since Linux 4.3.)` Unregister a memory address range from userfaultfd. The pages in the range must
     /// be "compatible" (see the description of `register`.)
@@ -232,8 +601,10 @@ impl Handle {
     /// * `EINVAL` There as an incompatible mapping in the specified address range.
     /// 
     /// * `EINVAL` There was no mapping in the specified address range.
-    pub fn unregister<T: Into<Range>>(&self, range: T) -> Result<(), Error> {
-        raw_interface::uffdio_unregister(self.0, range.into().into())
+    pub fn unregister<T: Into<Range>>(&self, range: T) -> Result<(), UffdError> {
+        let range = range.into();
+        range.check(PageSize::system())?;
+        raw_interface::uffdio_unregister(self.0, range.into())
     }
     /// `(Since  Linux 4.3.)` Atomically copy a continuous memory chunk into the userfault registered range and
     /// optionally wake up the blocked thread. The source and destination addresses and the number of bytes
@@ -254,6 +625,9 @@ impl Handle {
     /// * `UFFDIO_COPY_MODE_DONTWAKE`
     ///        Do not wake up the thread that waits for page-fault resolution
     /// 
+    /// * `COPY_WP` `(since Linux 5.7)`
+    ///        Write-protect the copied pages. The range must be registered with `REGISTER_WP`.
+    /// 
     /// Possible errors include:
     /// 
     /// * `EINVAL` Either dst or len was not a multiple of the system page size, or the range specified by src and
@@ -267,7 +641,12 @@ impl Handle {
     /// 
     /// * `ENOSPC` `(since Linux 4.11)`
     ///        The faulting process has exited at the time of a UFFDIO_COPY operation.
-    pub fn copy(&self, dst: *mut u8, src: *mut u8, len: u64, mode: CopyMode) -> Result<(), Error> {
+    ///
+    /// * `EAGAIN` A non-cooperative event such as a fork is pending. A copy interrupted after making
+    ///        progress is resumed, but if no progress can be made `Again` is returned; read the pending
+    ///        messages and retry. Pages copied before that stay in place, so the retry may fail with
+    ///        `EEXIST`.
+    pub fn copy(&self, dst: *mut u8, src: *mut u8, len: u64, mode: CopyMode) -> Result<(), UffdError> {
         raw_interface::uffdio_copy(
             self.0,
             raw_interface::defines::uffdio_copy {
@@ -291,13 +670,15 @@ impl Handle {
     /// Possible errors include:
     /// 
     /// * `EAGAIN` The  number of bytes zeroed (i.e., the value returned in the zeropage field) does not equal the
-    ///        value that was specified in the `range.len` field.
+    ///        value that was specified in the `range.len` field. The rest of the range is retried as long as
+    ///        progress is made; otherwise a non-cooperative event is pending and `Again` is returned until the
+    ///        pending messages have been read.
     /// 
     /// * `EINVAL` Either `range.start` or `range.len` was not a multiple of the system page size; or `range.len` was
     ///        zero; or the `range` specified was invalid.
     /// 
     /// * `EINVAL` An invalid bit was specified in the mode field.
-    pub fn zeropage<T: Into<Range>>(&self, range: T, mode: ZeropageMode) -> Result<(), Error> {
+    pub fn zeropage<T: Into<Range>>(&self, range: T, mode: ZeropageMode) -> Result<(), UffdError> {
         raw_interface::uffdio_zeropage(
             self.0,
             raw_interface::defines::uffdio_zeropage {
@@ -322,12 +703,174 @@ impl Handle {
     ///
     /// * `EINVAL` The  start  or the len field of the `range` structure was not a multiple of the system page
     ///        size; or len was zero; or the specified range was otherwise invalid.
-    pub fn wake<T: Into<Range>>(&self, range: T) -> Result<(), Error> {
+    pub fn wake<T: Into<Range>>(&self, range: T) -> Result<(), UffdError> {
         raw_interface::uffdio_wake(
             self.0,
             range.into().into()
         )
     }
+    /// `(Since Linux 5.7.)` Write-protect or write-unprotect a memory range registered with
+    /// `REGISTER_WP`.
+    ///
+    /// If `enable` is true the range is write-protected: subsequent writes to it block the writing thread
+    /// and generate a `Pagefault` message with `PAGEFAULT_FLAG_WP` set. If `enable` is false the protection
+    /// is removed and any threads blocked on write faults in the range are woken up.
+    ///
+    /// The following value may be bitwise ORed in mode to change the behavior of the `write_protect()`
+    /// operation:
+    ///
+    /// * `WRITEPROTECT_DONTWAKE` Do not wake up the thread that waits for page-fault resolution when
+    ///        removing protection.
+    ///
+    /// Possible errors include:
+    ///
+    /// * `EINVAL` Either `range.start` or `range.len` was not a multiple of the system page size; or
+    ///        `range.len` was zero; or the `range` specified was invalid.
+    ///
+    /// * `EINVAL` An invalid bit was specified in the mode field, or `WRITEPROTECT_DONTWAKE` was set
+    ///        together with `enable`.
+    ///
+    /// * `ENOENT` The range is not registered with `REGISTER_WP`.
+    ///
+    /// * `EAGAIN` The process was not able to make progress and the operation should be retried.
+    pub fn write_protect<T: Into<Range>>(&self, range: T, enable: bool, mode: WriteprotectMode) -> Result<(), UffdError> {
+        let wp = if enable { raw_interface::defines::UFFDIO_WRITEPROTECT_MODE_WP } else { 0 };
+        raw_interface::uffdio_writeprotect(
+            self.0,
+            raw_interface::defines::uffdio_writeprotect {
+                range: range.into().into(),
+                mode: mode.bits() | wp
+            }
+        )
+    }
+    /// `(Since Linux 5.13.)` Resolve a minor page fault by mapping the existing page cache pages of a range
+    /// registered with `REGISTER_MINOR` into the faulting range, without copying them.
+    ///
+    /// The contents of the pages can be modified through another mapping of the same file (for instance a
+    /// second mapping of a memfd) before calling `continue_range()`.
+    ///
+    /// The following value may be bitwise ORed in mode to change the behavior of the `continue_range()`
+    /// operation:
+    ///
+    /// * `CONTINUE_DONTWAKE` Do not wake up the thread that waits for page-fault resolution.
+    ///
+    /// Possible errors include:
+    ///
+    /// * `EEXIST` One or more pages in the range were already mapped.
+    ///
+    /// * `EFAULT` One or more pages in the range were not present in the page cache.
+    ///
+    /// * `EINVAL` Either `range.start` or `range.len` was not a multiple of the system page size; or
+    ///        `range.len` was zero; or the `range` specified was invalid.
+    ///
+    /// * `EINVAL` An invalid bit was specified in the mode field.
+    ///
+    /// * `ENOENT` The faulting process has changed its virtual memory layout simultaneously with an
+    ///        outstanding `continue_range()` operation.
+    ///
+    /// * `ENOSPC` The faulting process has exited at the time of a `continue_range()` operation.
+    ///
+    /// * `EAGAIN` A non-cooperative event such as a fork is pending and no progress could be made; read
+    ///        the pending messages and retry.
+    pub fn continue_range<T: Into<Range>>(&self, range: T, mode: ContinueMode) -> Result<(), UffdError> {
+        raw_interface::uffdio_continue(
+            self.0,
+            raw_interface::defines::uffdio_continue {
+                range: range.into().into(),
+                mode: mode.bits(),
+                mapped: 0
+            }
+        )
+    }
+    /// `(Since Linux 6.8.)` Atomically move a continuous memory chunk into the userfault registered range by
+    /// remapping the source pages instead of copying them, and optionally wake up the blocked thread. The
+    /// `move_pages` option must be enabled on the `Builder`.
+    ///
+    /// After a successful move the source range is left unmapped, as if it had been `MADV_DONTNEED`ed. The
+    /// source pages must be private anonymous pages that are not shared with another process, such as a
+    /// staging buffer the monitor filled itself.
+    ///
+    /// The following values may be bitwise ORed in mode to change the behavior of the `move_pages()`
+    /// operation:
+    ///
+    /// * `MOVE_DONTWAKE` Do not wake up the thread that waits for page-fault resolution.
+    ///
+    /// * `MOVE_ALLOW_SRC_HOLES` Skip unpopulated pages in the source range instead of failing with `ENOENT`.
+    ///
+    /// If the move fails part way through, the returned `MoveError` records how many bytes were moved
+    /// before the failure.
+    ///
+    /// Possible errors include:
+    ///
+    /// * `EINVAL` Either `dst`, `src` or `len` was not a multiple of the system page size, or the ranges
+    ///        specified were invalid or overlap.
+    ///
+    /// * `EINVAL` An invalid bit was specified in the mode field.
+    ///
+    /// * `EBUSY` A source page is shared with another process or pinned and cannot be moved.
+    ///
+    /// * `EEXIST` A page is already present in the destination range.
+    ///
+    /// * `ENOENT` A page in the source range is not populated and `MOVE_ALLOW_SRC_HOLES` was not set.
+    ///
+    /// * `ESRCH` The faulting process has exited at the time of a `move_pages()` operation.
+    ///
+    /// * `EAGAIN` A non-cooperative event such as a fork is pending and no progress could be made; read
+    ///        the pending messages and retry the part of the range that was not moved.
+    pub fn move_pages(&self, dst: *mut u8, src: *mut u8, len: u64, mode: MoveMode) -> Result<(), MoveError> {
+        raw_interface::uffdio_move(
+            self.0,
+            raw_interface::defines::uffdio_move {
+                dst: dst as u64,
+                src: src as u64,
+                len: len,
+                mode: mode.bits(),
+                move_: 0
+            }
+        ).map_err(|(moved, error)| MoveError { moved: moved, error: error })
+    }
+    /// `(Since Linux 6.6.)` Mark a memory range registered with userfaultfd as hardware-poisoned, and
+    /// optionally wake up the blocked thread. The `poison` option must be enabled on the `Builder`.
+    ///
+    /// Instead of reading the contents of the range, threads accessing it receive a `SIGBUS`, just as if
+    /// the memory had suffered an uncorrectable hardware error. This is useful when the monitor is unable
+    /// to produce the contents of a page and the faulting thread should fail rather than block forever or
+    /// observe bogus data.
+    ///
+    /// The following value may be bitwise ORed in mode to change the behavior of the `poison()` operation:
+    ///
+    /// * `POISON_DONTWAKE` Do not wake up the thread that waits for page-fault resolution.
+    ///
+    /// Possible errors include:
+    ///
+    /// * `EEXIST` One or more pages in the range were already mapped.
+    ///
+    /// * `EINVAL` Either `range.start` or `range.len` was not a multiple of the system page size; or
+    ///        `range.len` was zero; or the `range` specified was invalid.
+    ///
+    /// * `EINVAL` An invalid bit was specified in the mode field.
+    ///
+    /// * `ENOENT` The faulting process has changed its virtual memory layout simultaneously with an
+    ///        outstanding `poison()` operation.
+    ///
+    /// * `ENOSPC` The faulting process has exited at the time of a `poison()` operation.
+    ///
+    /// * `EAGAIN` A non-cooperative event such as a fork is pending and no progress could be made; read
+    ///        the pending messages and retry.
+    pub fn poison<T: Into<Range>>(&self, range: T, mode: PoisonMode) -> Result<(), UffdError> {
+        raw_interface::uffdio_poison(
+            self.0,
+            raw_interface::defines::uffdio_poison {
+                range: range.into().into(),
+                mode: mode.bits(),
+                updated: 0
+            }
+        )
+    }
+    /// Get an `EventedFd` for registering the handle with a mio 0.6 `Poll`.
+    ///
+    /// With the `mio08` or `mio1` features the handle implements `event::Source` and can be registered
+    /// with the `Registry` of newer mio versions directly.
     #[cfg(feature = "mio")]
     pub fn get_eventfd<'a>(&'a self) -> EventedFd<'a> {
         EventedFd(&self.0)
@@ -335,35 +878,155 @@ impl Handle {
 
     pub fn read_message(&self) -> Result<Message, Error> {
         use std::{mem,slice};
-        use std::io::Read;
-        //let m: uffd_msg = unsafe { std::mem::uninitialized() };
         let mut m: uffd_msg = uffd_msg::default();
-        let mut s = self;
         unsafe {
-            std::io::Read::read(&mut s, slice::from_raw_parts_mut(&mut m as *mut _ as *mut u8, mem::size_of::<uffd_msg>()))?;
-            match m.event as u32 { // TODO: fix the types here
-                raw_interface::defines::UFFD_EVENT_PAGEFAULT => {
-                    Ok(Message::Pagefault(mem::transmute(m)))
-                }
-                raw_interface::defines::UFFD_EVENT_FORK => {
+            raw_interface::read(self.0, slice::from_raw_parts_mut(&mut m as *mut _ as *mut u8, mem::size_of::<uffd_msg>()))?;
+        }
+        Ok(Message::from_raw(m))
+    }
 
-                    Ok(Message::Fork(mem::transmute(m)))
-                }
-                raw_interface::defines::UFFD_EVENT_REMAP => {
+    /// Switch the handle to non-blocking mode, as if it had been created with `non_block`. The flag is
+    /// shared with every duplicate of the descriptor.
+    pub(crate) fn set_non_blocking(&self) -> Result<(), Error> {
+        let flags = unsafe { libc::fcntl(self.0, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(self.0, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
 
-                    Ok(Message::Remap(mem::transmute(m)))
-                }
-                raw_interface::defines::UFFD_EVENT_REMOVE => {
+    /// Read as many queued messages as fit in `buf` with a single `read(2)` call, replacing its previous
+    /// contents. Returns the number of messages read.
+    ///
+    /// Like `read_message()`, this blocks until at least one message is available unless the handle was
+    /// created with `non_block`, in which case an error of kind `WouldBlock` is returned when the queue is
+    /// empty.
+    pub fn read_messages(&self, buf: &mut MessageBuffer) -> Result<usize, Error> {
+        use std::{mem,slice};
+        // Messages left over from the previous read may own descriptors that must be closed
+        drop(buf.drain());
+        buf.start = 0;
+        buf.len = 0;
+        let n = unsafe {
+            raw_interface::read(self.0, slice::from_raw_parts_mut(
+                buf.msgs.as_mut_ptr() as *mut u8,
+                buf.msgs.len() * mem::size_of::<uffd_msg>()
+            ))?
+        };
+        buf.len = n / mem::size_of::<uffd_msg>();
+        Ok(buf.len)
+    }
+}
 
-                    Ok(Message::Remove(mem::transmute(m)))
-                }
-                raw_interface::defines::UFFD_EVENT_UNMAP => {
-                    Ok(Message::Unmap(mem::transmute(m)))
-                }
-                _ => { unimplemented!() }
-            }
+/// A reusable buffer for reading messages in batches with `Handle::read_messages()`.
+pub struct MessageBuffer {
+    msgs: Vec<uffd_msg>,
+    start: usize,
+    len: usize
+}
+
+impl MessageBuffer {
+    /// Create a buffer holding up to `capacity` messages.
+    pub fn new(capacity: usize) -> Self {
+        MessageBuffer {
+            msgs: vec![uffd_msg::default(); capacity],
+            start: 0,
+            len: 0
         }
     }
+
+    pub fn capacity(&self) -> usize {
+        self.msgs.len()
+    }
+
+    /// The number of messages read by the last call to `read_messages()` that have not been drained yet.
+    pub fn len(&self) -> usize {
+        self.len - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.len
+    }
+
+    /// Take the messages read by the last call to `read_messages()`.
+    ///
+    /// Each message is only handed out once, since `Fork` messages own the descriptor they carry. Messages
+    /// that are not consumed from the iterator are dropped along with it, closing their descriptors.
+    pub fn drain<'a>(&'a mut self) -> Drain<'a> {
+        Drain { buf: self }
+    }
+}
+
+impl Drop for MessageBuffer {
+    fn drop(&mut self) {
+        drop(self.drain());
+    }
+}
+
+impl std::fmt::Debug for MessageBuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MessageBuffer")
+            .field("capacity", &self.msgs.len())
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Iterator over the messages of a `MessageBuffer`, returned by `MessageBuffer::drain()`.
+///
+/// Messages are decoded one at a time, as they are taken from the buffer.
+#[derive(Debug)]
+pub struct Drain<'a> {
+    buf: &'a mut MessageBuffer
+}
+
+impl<'a> Iterator for Drain<'a> {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        if self.buf.start == self.buf.len {
+            return None;
+        }
+        let m = self.buf.msgs[self.buf.start];
+        self.buf.start += 1;
+        Some(Message::from_raw(m))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.buf.len(), Some(self.buf.len()))
+    }
+}
+
+impl<'a> Drop for Drain<'a> {
+    fn drop(&mut self) {
+        for _ in self {}
+    }
+}
+
+/// Error returned by `Handle::move_pages()`.
+#[derive(Debug)]
+pub struct MoveError {
+    /// Number of bytes that were moved before the error occurred.
+    pub moved: u64,
+    pub error: UffdError
+}
+
+impl std::fmt::Display for MoveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "UFFDIO_MOVE failed after moving {} bytes: {}", self.moved, self.error)
+    }
+}
+
+impl std::error::Error for MoveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<MoveError> for Error {
+    fn from(e: MoveError) -> Error {
+        e.error.into()
+    }
 }
 
 use raw_interface::defines::uffd_msg;
@@ -374,7 +1037,38 @@ pub enum Message {
     Fork(ForkMessage),
     Remap(RemapMessage),
     Remove(RemoveMessage),
-    Unmap(UnmapMessage)
+    Unmap(UnmapMessage),
+    /// An event this version of the crate does not know how to decode, for instance one added by a newer
+    /// kernel. `raw` holds the complete `uffd_msg` as read from the userfaultfd object.
+    Unknown { event: u8, raw: [u8; 32] }
+}
+
+impl Message {
+    fn from_raw(m: uffd_msg) -> Message {
+        use std::mem;
+        unsafe {
+            match m.event as u32 { // TODO: fix the types here
+                raw_interface::defines::UFFD_EVENT_PAGEFAULT => {
+                    Message::Pagefault(mem::transmute(m))
+                }
+                raw_interface::defines::UFFD_EVENT_FORK => {
+                    Message::Fork(mem::transmute(m))
+                }
+                raw_interface::defines::UFFD_EVENT_REMAP => {
+                    Message::Remap(mem::transmute(m))
+                }
+                raw_interface::defines::UFFD_EVENT_REMOVE => {
+                    Message::Remove(mem::transmute(m))
+                }
+                raw_interface::defines::UFFD_EVENT_UNMAP => {
+                    Message::Unmap(mem::transmute(m))
+                }
+                event => {
+                    Message::Unknown { event: event as u8, raw: mem::transmute::<uffd_msg, [u8; 32]>(m) }
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -386,8 +1080,36 @@ pub struct PagefaultMessage {
     _res3: u32,
     pub flags: u64,
     pub address: u64,
-    _pad: u64
+    ptid: u32,
+    _pad: u32
 }
+
+impl PagefaultMessage {
+    /// Decode the `flags` field of the message.
+    ///
+    /// `PAGEFAULT_FLAG_WP` is set when the fault was caused by a write to a page write-protected with
+    /// `write_protect()`, and `PAGEFAULT_FLAG_WRITE` when the faulting access was a write.
+    pub fn pagefault_flags(&self) -> PagefaultFlags {
+        PagefaultFlags::from_bits_truncate(self.flags)
+    }
+
+    /// The thread id of the faulting thread.
+    ///
+    /// Only reported when the `thread_id` option was enabled on the `Builder` `(since Linux 4.14)`;
+    /// otherwise `None` is returned.
+    pub fn thread_id(&self) -> Option<libc::pid_t> {
+        if self.ptid == 0 {
+            None
+        } else {
+            Some(self.ptid as libc::pid_t)
+        }
+    }
+}
+
+/// Sent to the parent's userfaultfd object when a process with registered ranges forks and the
+/// `event_fork` option is enabled. The kernel installs a new userfaultfd object for the child in the
+/// monitor process, which is owned by this message: it is closed when the message is dropped unless it
+/// is taken with `into_handle()`.
 #[derive(Debug)]
 #[repr(C)]
 pub struct ForkMessage {
@@ -395,11 +1117,42 @@ pub struct ForkMessage {
     _res1: u8,
     _res2: u16,
     _res3: u32,
-    pub ufd: u32,
+    ufd: u32,
     _pad1: u32,
     _pad2: u64,
     _pad3: u64,
 }
+
+impl ForkMessage {
+    /// Take ownership of the child's userfaultfd object.
+    ///
+    /// The child's ranges stay registered with the returned handle, and its faults must be resolved
+    /// through it, otherwise the child blocks on its first fault.
+    pub fn into_handle(self) -> Handle {
+        Handle(self.into_raw_fd())
+    }
+}
+
+impl AsRawFd for ForkMessage {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ufd as RawFd
+    }
+}
+
+impl IntoRawFd for ForkMessage {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.as_raw_fd();
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl Drop for ForkMessage {
+    fn drop(&mut self) {
+        use sc::platform::nr::CLOSE;
+        unsafe { syscall!(CLOSE, self.ufd); }
+    }
+}
 #[derive(Debug)]
 #[repr(C)]
 pub struct RemapMessage {
@@ -443,6 +1196,7 @@ fn assert_sizes() {
     assert!(size_of::<RemapMessage>() == size_of::<raw_interface::defines::uffd_msg>());
     assert!(size_of::<RemoveMessage>() == size_of::<raw_interface::defines::uffd_msg>());
     assert!(size_of::<UnmapMessage>() == size_of::<raw_interface::defines::uffd_msg>());
+    assert!(size_of::<[u8; 32]>() == size_of::<raw_interface::defines::uffd_msg>());
 }
 
 impl io::Read for Handle {
@@ -453,11 +1207,34 @@ impl io::Read for Handle {
 
 impl<'a> io::Read for &'a Handle {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-            println!("DANGER ZONE");
         raw_interface::read(self.0, buf) 
     }
 }
 
+// Implements `event::Source` for the given version of mio, so the handle can be registered with a
+// `Poll` registry directly
+#[cfg(any(feature = "mio08", feature = "mio1"))]
+macro_rules! impl_mio_source {
+    ($mio:ident) => {
+        impl $mio::event::Source for Handle {
+            fn register(&mut self, registry: &$mio::Registry, token: $mio::Token, interests: $mio::Interest) -> io::Result<()> {
+                $mio::unix::SourceFd(&self.0).register(registry, token, interests)
+            }
+            fn reregister(&mut self, registry: &$mio::Registry, token: $mio::Token, interests: $mio::Interest) -> io::Result<()> {
+                $mio::unix::SourceFd(&self.0).reregister(registry, token, interests)
+            }
+            fn deregister(&mut self, registry: &$mio::Registry) -> io::Result<()> {
+                $mio::unix::SourceFd(&self.0).deregister(registry)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "mio08")]
+impl_mio_source!(mio08);
+#[cfg(feature = "mio1")]
+impl_mio_source!(mio1);
+
 impl Drop for Handle {
     fn drop(&mut self) {
         use sc::platform::nr::CLOSE;
@@ -0,0 +1,483 @@
+use libc;
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use {Handle, Message, MessageBuffer, PageSize, Range, UffdError};
+use shutdown::{self, ShutdownFd};
+use {CopyMode, PoisonMode, COPY_DONTWAKE, POISON_DONTWAKE, ZEROPAGE_DONTWAKE};
+
+/// Produces the contents of pages on demand for a `Monitor`.
+pub trait PageSource: Send + Sync {
+    /// Fill `page` with the contents of the page starting at `addr`.
+    ///
+    /// `page` is as long as the page size the monitor was configured with. If an error is returned, the
+    /// page is poisoned when the handle supports it, so the faulting thread receives a `SIGBUS`;
+    /// otherwise the monitor stops and reports the error.
+    fn fill(&self, addr: usize, page: &mut [u8]) -> Result<(), Error>;
+}
+
+impl<F> PageSource for F where F: Fn(usize, &mut [u8]) -> Result<(), Error> + Send + Sync {
+    fn fill(&self, addr: usize, page: &mut [u8]) -> Result<(), Error> {
+        self(addr, page)
+    }
+}
+
+impl<S: PageSource + ?Sized> PageSource for Arc<S> {
+    fn fill(&self, addr: usize, page: &mut [u8]) -> Result<(), Error> {
+        (**self).fill(addr, page)
+    }
+}
+
+/// Configuration for a `Monitor`.
+#[derive(Debug)]
+pub struct MonitorBuilder {
+    page_size: PageSize,
+    batch_size: usize,
+    threads: usize,
+    follow_fork: bool,
+}
+
+impl MonitorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    builder_methods!{
+        batch_size: usize,
+        threads: usize,
+        follow_fork: bool
+    }
+
+    /// The size of the pages filled and copied in, the system page size by default.
+    ///
+    /// Fault addresses are aligned down to it, so it must be the page size of the registered ranges. For
+    /// ranges backed by hugetlbfs it is the huge page size, for instance `Region::page_size()` of a region
+    /// created with `Backing::Hugetlb`.
+    pub fn page_size(mut self, page_size: PageSize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Start a handler thread resolving the missing page faults of `handle` with `source`.
+    ///
+    /// Each batch of faults read from the handle is filled and copied in with `COPY_DONTWAKE`, and the
+    /// faulting threads are woken once the whole batch has been installed. A fault that races with
+    /// another resolution of the same page (`EEXIST`) is treated as resolved.
+    ///
+    /// With `threads` greater than one, faults are instead filled by a pool of that many threads, each
+    /// owning the pages whose index modulo the pool size is its own. A fault on a page that is already
+    /// queued or being filled is not dispatched again, as the thread that raised it is woken together with
+    /// the first one.
+    ///
+    /// With `follow_fork`, the userfaultfd objects of forked children (see the `event_fork` option of
+    /// `Builder`) are served by the same thread with the same source. Without it they are closed, which
+    /// unregisters the child's ranges so that it does not block on them. Children are forgotten once they
+    /// have exited.
+    ///
+    /// A fault that cannot be resolved while a fork, `mremap()` or similar event is pending (`EAGAIN`) is
+    /// retried once the event has been read. A fork in the monitored process itself blocks until the
+    /// monitor reads its event, and glibc holds the allocator locks in the meantime: with `event_fork`,
+    /// `source` must then not allocate, or the monitor and the forking thread deadlock.
+    ///
+    /// The handle is switched to non-blocking mode if it was not created with the `non_block` option. An
+    /// error of kind `InvalidInput` is returned if `batch_size` is zero.
+    pub fn spawn<S: PageSource + 'static>(self, handle: Handle, source: S) -> Result<Monitor, Error> {
+        if self.batch_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "monitor batch size is zero"));
+        }
+        handle.set_non_blocking()?;
+        let handle = Arc::new(handle);
+        let shutdown = Arc::new(ShutdownFd::new()?);
+        let source = Arc::new(source);
+        let pool = if self.threads > 1 {
+            Some(Pool::spawn(self.threads, &source, self.page_size.bytes(), &shutdown)?)
+        } else {
+            None
+        };
+        let children = Arc::new(AtomicUsize::new(0));
+        let worker = Worker {
+            handle: handle.clone(),
+            children: Vec::new(),
+            child_count: children.clone(),
+            source,
+            pool,
+            shutdown: shutdown.clone(),
+            page: vec![0; self.page_size.bytes()],
+            buf: MessageBuffer::new(self.batch_size),
+            fds: Vec::new(),
+            resolved: Vec::with_capacity(self.batch_size),
+            deferred: Vec::with_capacity(self.batch_size),
+            follow_fork: self.follow_fork,
+        };
+        let thread = thread::Builder::new()
+            .name("userfaultfd-monitor".into())
+            .spawn(move || worker.run())?;
+        Ok(Monitor { handle, children, shutdown, thread: Some(thread) })
+    }
+}
+
+impl Default for MonitorBuilder {
+    fn default() -> Self {
+        Self {
+            page_size: PageSize::system(),
+            batch_size: 64,
+            threads: 1,
+            follow_fork: false,
+        }
+    }
+}
+
+/// A running fault handler thread, created with `MonitorBuilder::spawn()`.
+///
+/// The thread stops when the monitor is dropped or `shutdown()` is called. Ranges must stay registered
+/// with `handle()` for as long as they are accessed.
+#[derive(Debug)]
+pub struct Monitor {
+    handle: Arc<Handle>,
+    children: Arc<AtomicUsize>,
+    shutdown: Arc<ShutdownFd>,
+    thread: Option<JoinHandle<Result<(), UffdError>>>,
+}
+
+impl Monitor {
+    /// The handle served by the monitor, for registering and unregistering ranges.
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    /// The number of forked children currently served, with `follow_fork`.
+    pub fn children(&self) -> usize {
+        self.children.load(Ordering::Relaxed)
+    }
+
+    /// Stop the handler thread and report why it stopped, if it failed before being asked to.
+    pub fn shutdown(mut self) -> Result<(), UffdError> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<(), UffdError> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(())
+        };
+        self.shutdown.signal();
+        thread.join().unwrap_or_else(|_| {
+            Err(UffdError::Io(Error::other("userfaultfd monitor thread panicked")))
+        })
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+struct Worker<S> {
+    handle: Arc<Handle>,
+    children: Vec<Arc<Handle>>,
+    child_count: Arc<AtomicUsize>,
+    source: Arc<S>,
+    pool: Option<Pool>,
+    shutdown: Arc<ShutdownFd>,
+    page: Vec<u8>,
+    buf: MessageBuffer,
+    // Reused across iterations: a forking thread holds the allocator locks until its fork event is
+    // read, so nothing between poll() and read may allocate
+    fds: Vec<libc::pollfd>,
+    resolved: Vec<usize>,
+    deferred: Vec<usize>,
+    follow_fork: bool,
+}
+
+impl<S: PageSource> Worker<S> {
+    fn run(mut self) -> Result<(), UffdError> {
+        let ret = self.poll();
+        match self.pool.take() {
+            Some(pool) => ret.and(pool.join()),
+            None => ret
+        }
+    }
+
+    fn poll(&mut self) -> Result<(), UffdError> {
+        loop {
+            let fds = &mut self.fds;
+            fds.clear();
+            fds.push(self.shutdown.pollfd());
+            fds.push(libc::pollfd { fd: self.handle.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+            fds.extend(self.children.iter().map(|c| libc::pollfd { fd: c.as_raw_fd(), events: libc::POLLIN, revents: 0 }));
+            if !shutdown::poll(fds)? {
+                return Ok(());
+            }
+            if self.fds[1].revents != 0 {
+                let handle = self.handle.clone();
+                if !self.serve(&handle)? {
+                    return Ok(());
+                }
+            }
+            // Walk the polled children backwards so exited ones can be removed in place; children
+            // forked while serving above are appended and only polled on the next iteration. An exited
+            // child never becomes readable, so the quiet ones are probed instead
+            for i in (0..self.fds.len() - 2).rev() {
+                let revents = self.fds[i + 2].revents;
+                let alive = if revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+                    false
+                } else if revents != 0 {
+                    let child = self.children[i].clone();
+                    self.serve(&child)?
+                } else {
+                    is_alive(&self.children[i])
+                };
+                if !alive {
+                    self.children.swap_remove(i);
+                }
+            }
+            self.child_count.store(self.children.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Resolve the messages queued on `handle`. Returns false once the process behind it is gone.
+    fn serve(&mut self, handle: &Arc<Handle>) -> Result<bool, UffdError> {
+        let page_size = self.page.len();
+        let mut resolved = std::mem::take(&mut self.resolved);
+        let mut deferred = std::mem::take(&mut self.deferred);
+        let ret = loop {
+            match handle.read_messages(&mut self.buf) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock && !deferred.is_empty() => {
+                    // The event holding up the deferred faults is either not queued yet, or has been read
+                    // and the thread that raised it has yet to finish
+                    let mut fds = [self.shutdown.pollfd(), libc::pollfd { fd: handle.as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+                    match shutdown::wait(&mut fds, 1) {
+                        Ok(true) => {}
+                        Ok(false) => break Ok(true),
+                        Err(e) => break Err(e)
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break Ok(true),
+                Err(e) => break Err(UffdError::Io(e))
+            }
+            let mut alive = Ok(true);
+            let retries = deferred.len();
+            for i in 0..retries {
+                if !matches!(alive, Ok(true)) {
+                    break;
+                }
+                let addr = deferred[i];
+                let ret = resolve(handle, &*self.source, &mut self.page, addr, false);
+                alive = record(ret, addr, &mut resolved, &mut deferred);
+            }
+            deferred.drain(..retries);
+            let mut forked = Vec::new();
+            for msg in self.buf.drain() {
+                match msg {
+                    Message::Pagefault(fault) => {
+                        let addr = fault.address as usize & !(page_size - 1);
+                        if let Some(ref pool) = self.pool {
+                            pool.dispatch(handle, addr);
+                            continue;
+                        }
+                        // Every thread faulting on a page queues its own message
+                        if !matches!(alive, Ok(true)) || resolved.contains(&addr) || deferred.contains(&addr) {
+                            continue;
+                        }
+                        let ret = resolve(handle, &*self.source, &mut self.page, addr, false);
+                        alive = record(ret, addr, &mut resolved, &mut deferred);
+                    }
+                    Message::Fork(fork) if self.follow_fork => {
+                        // The child inherits the flags the parent was created with, not those set later
+                        let child = fork.into_handle();
+                        if child.set_non_blocking().is_ok() {
+                            forked.push(Arc::new(child));
+                        }
+                    }
+                    // Everything else, including unfollowed children, is dropped here
+                    _ => {}
+                }
+            }
+            self.children.extend(forked);
+            let ret = alive.and_then(|alive| if alive { wake(handle, &mut resolved, page_size) } else { Ok(false) });
+            resolved.clear();
+            // Only stay on this handle until the faults held up by an event are resolved
+            match ret {
+                Ok(true) if !deferred.is_empty() => {}
+                ret => break ret
+            }
+        };
+        deferred.clear();
+        self.resolved = resolved;
+        self.deferred = deferred;
+        ret
+    }
+}
+
+/// Record the outcome of resolving the fault on the page at `addr`. Returns false once the process is gone
+fn record(ret: Result<(), UffdError>, addr: usize, resolved: &mut Vec<usize>, deferred: &mut Vec<usize>)
+    -> Result<bool, UffdError>
+{
+    match ret {
+        Ok(()) | Err(UffdError::PageExists) => resolved.push(addr),
+        // An event such as a fork is pending, retry once it has been read
+        Err(UffdError::Again) => deferred.push(addr),
+        // The range went away under the fault, there is nothing left to wake
+        Err(UffdError::MappingChanged) => {}
+        Err(UffdError::ProcessExited) => return Ok(false),
+        Err(e) => return Err(e)
+    }
+    Ok(true)
+}
+
+/// Whether the process behind a child handle is still running. Operations on a handle whose process has
+/// exited fail with `ESRCH`, while a zeropage on the page holding this function fails with `ENOENT` in a
+/// live child: text is a private file mapping, which can never be registered. A live child with an event
+/// pending fails with `EAGAIN` instead.
+fn is_alive(handle: &Handle) -> bool {
+    let page_size = PageSize::system();
+    let text = page_size.align_down(is_alive as *const () as usize);
+    let range = Range { start: text as *mut u8, len: page_size.bytes() };
+    !matches!(handle.zeropage(range, ZEROPAGE_DONTWAKE), Err(UffdError::ProcessExited))
+}
+
+/// Fill the page at `addr` from `source` and install it, or poison it if the source fails
+fn resolve<S: PageSource>(handle: &Handle, source: &S, page: &mut [u8], addr: usize, wake: bool)
+    -> Result<(), UffdError>
+{
+    match source.fill(addr, page) {
+        Ok(()) => {
+            let mode = if wake { CopyMode::empty() } else { COPY_DONTWAKE };
+            handle.copy(addr as *mut u8, page.as_mut_ptr(), page.len() as u64, mode)
+        }
+        Err(e) => {
+            let range = Range { start: addr as *mut u8, len: page.len() };
+            let mode = if wake { PoisonMode::empty() } else { POISON_DONTWAKE };
+            match handle.poison(range, mode) {
+                Err(UffdError::Unsupported) | Err(UffdError::InvalidArgument) => Err(UffdError::Io(e)),
+                x => x
+            }
+        }
+    }
+}
+
+/// Wake the threads blocked on `pages`, coalescing adjacent pages into a single wake
+fn wake(handle: &Handle, pages: &mut Vec<usize>, page_size: usize) -> Result<bool, UffdError> {
+    pages.sort();
+    pages.dedup();
+    let mut i = 0;
+    while i < pages.len() {
+        let start = pages[i];
+        let mut end = start + page_size;
+        i += 1;
+        while i < pages.len() && pages[i] == end {
+            end += page_size;
+            i += 1;
+        }
+        match handle.wake(Range { start: start as *mut u8, len: end - start }) {
+            Ok(()) | Err(UffdError::MappingChanged) => {}
+            Err(UffdError::ProcessExited) => return Ok(false),
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(true)
+}
+
+/// A fault to resolve: the handle it was read from and the address of the page
+type Fault = (Arc<Handle>, usize);
+type InFlight = Arc<Mutex<HashSet<(RawFd, usize)>>>;
+
+/// The fault resolution threads of a monitor with more than one thread
+struct Pool {
+    shards: Vec<(Sender<Fault>, InFlight)>,
+    threads: Vec<JoinHandle<Result<(), UffdError>>>,
+    page_size: usize,
+}
+
+impl Pool {
+    fn spawn<S: PageSource + 'static>(threads: usize, source: &Arc<S>, page_size: usize, shutdown: &Arc<ShutdownFd>)
+        -> Result<Pool, Error>
+    {
+        let mut pool = Pool { shards: Vec::new(), threads: Vec::new(), page_size };
+        for i in 0..threads {
+            let (tx, rx) = mpsc::channel();
+            let in_flight = InFlight::default();
+            let shard = Shard {
+                rx,
+                in_flight: in_flight.clone(),
+                source: source.clone(),
+                page_size,
+                shutdown: shutdown.clone(),
+            };
+            let thread = thread::Builder::new()
+                .name(format!("userfaultfd-monitor-{}", i))
+                .spawn(move || shard.run())?;
+            pool.shards.push((tx, in_flight));
+            pool.threads.push(thread);
+        }
+        Ok(pool)
+    }
+
+    fn dispatch(&self, handle: &Arc<Handle>, addr: usize) {
+        let (ref tx, ref in_flight) = self.shards[addr / self.page_size % self.shards.len()];
+        if in_flight.lock().unwrap().insert((handle.as_raw_fd(), addr)) {
+            // A failed send means the shard stopped on an error, which join() reports
+            let _ = tx.send((handle.clone(), addr));
+        }
+    }
+
+    /// Stop the threads once they have drained their queues, and report the first error
+    fn join(self) -> Result<(), UffdError> {
+        drop(self.shards);
+        let mut ret = Ok(());
+        for thread in self.threads {
+            let r = thread.join().unwrap_or_else(|_| {
+                Err(UffdError::Io(Error::other("userfaultfd monitor thread panicked")))
+            });
+            ret = ret.and(r);
+        }
+        ret
+    }
+}
+
+struct Shard<S> {
+    rx: Receiver<Fault>,
+    in_flight: InFlight,
+    source: Arc<S>,
+    page_size: usize,
+    shutdown: Arc<ShutdownFd>,
+}
+
+impl<S: PageSource> Shard<S> {
+    fn run(self) -> Result<(), UffdError> {
+        let mut page = vec![0; self.page_size];
+        for (handle, addr) in self.rx.iter() {
+            let range = Range { start: addr as *mut u8, len: self.page_size };
+            let ret = loop {
+                match resolve(&handle, &*self.source, &mut page, addr, true) {
+                    // An event such as a fork is pending, which the monitor thread reads
+                    Err(UffdError::Again) => {
+                        if !shutdown::wait(&mut [self.shutdown.pollfd()], 1)? {
+                            return Ok(());
+                        }
+                    }
+                    // Unlike a successful copy, a failed one does not wake the faulting threads
+                    Err(UffdError::PageExists) => break handle.wake(range),
+                    ret => break ret
+                }
+            };
+            self.in_flight.lock().unwrap().remove(&(handle.as_raw_fd(), addr));
+            match ret {
+                Ok(()) | Err(UffdError::MappingChanged) | Err(UffdError::ProcessExited) => {}
+                Err(e) => {
+                    // Stop the monitor so the error is reported by shutdown()
+                    self.shutdown.signal();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
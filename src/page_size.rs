@@ -0,0 +1,83 @@
+use libc;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind};
+
+/// The size of the pages backing a mapping, always a power of two.
+///
+/// Most mappings use the base page size of the system, which is not 4096 everywhere (arm64 and ppc64
+/// kernels are commonly built with 16K or 64K pages). Mappings of hugetlbfs files use the huge page size
+/// of the file system, and every range passed to the kernel for them must be aligned to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageSize(usize);
+
+impl PageSize {
+    /// A page size of `bytes`, if it is a power of two.
+    pub fn new(bytes: usize) -> Option<PageSize> {
+        if bytes.is_power_of_two() { Some(PageSize(bytes)) } else { None }
+    }
+
+    /// The base page size of the system.
+    pub fn system() -> PageSize {
+        PageSize(unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize })
+    }
+
+    /// The page size of the mapping containing `addr` in the current process, read from the
+    /// `KernelPageSize` field of `/proc/self/smaps`. Fails with `NotFound` if nothing is mapped at `addr`.
+    pub fn of(addr: *const u8) -> Result<PageSize, Error> {
+        let addr = addr as usize;
+        let smaps = BufReader::new(File::open("/proc/self/smaps")?);
+        let mut inside = false;
+        for line in smaps.lines() {
+            let line = line?;
+            if let Some(range) = vma_range(&line) {
+                inside = range.0 <= addr && addr < range.1;
+            } else if inside && line.starts_with("KernelPageSize:") {
+                let kb = line["KernelPageSize:".len()..].trim().trim_end_matches("kB").trim();
+                return kb.parse::<usize>().ok()
+                    .and_then(|kb| PageSize::new(kb * 1024))
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("bad smaps line: {}", line)));
+            }
+        }
+        Err(Error::new(ErrorKind::NotFound, format!("no mapping contains {:#x}", addr)))
+    }
+
+    pub fn bytes(self) -> usize {
+        self.0
+    }
+
+    /// Round `addr` down to a page boundary.
+    pub fn align_down(self, addr: usize) -> usize {
+        addr & !(self.0 - 1)
+    }
+
+    /// Round `addr` up to a page boundary.
+    pub fn align_up(self, addr: usize) -> usize {
+        self.align_down(addr + self.0 - 1)
+    }
+
+    pub fn is_aligned(self, addr: usize) -> bool {
+        addr & (self.0 - 1) == 0
+    }
+}
+
+impl fmt::Display for PageSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            n if n >= 1 << 30 && n % (1 << 30) == 0 => write!(f, "{}G", n >> 30),
+            n if n >= 1 << 20 && n % (1 << 20) == 0 => write!(f, "{}M", n >> 20),
+            n if n >= 1 << 10 && n % (1 << 10) == 0 => write!(f, "{}K", n >> 10),
+            n => write!(f, "{}", n)
+        }
+    }
+}
+
+/// Parse the address range of a mapping header line of `/proc/<pid>/smaps`, such as
+/// `7f2c4a000000-7f2c4a021000 rw-p 00000000 00:00 0`
+fn vma_range(line: &str) -> Option<(usize, usize)> {
+    let range = line.split_whitespace().next()?;
+    let mut bounds = range.splitn(2, '-');
+    let start = usize::from_str_radix(bounds.next()?, 16).ok()?;
+    let end = usize::from_str_radix(bounds.next()?, 16).ok()?;
+    Some((start, end))
+}
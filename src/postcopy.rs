@@ -0,0 +1,222 @@
+//! Post-copy migration of a memory region between processes.
+//!
+//! The destination registers its region and starts using it right away. Pages it touches before they
+//! have arrived are requested from the source and installed as soon as they come in, while the source
+//! keeps pushing all the remaining pages in the background.
+//!
+//! The wire format is little-endian: the source first sends the page size and the page count as two
+//! `u64`s, then pages as a `u64` byte offset followed by the page contents, and finally `END` in place of
+//! an offset. The destination sends requests as the `u64` byte offset of the page it faulted on.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+use {CopyMode, Handle, Message, MessageBuffer, PageSize, Range, UffdError};
+use REGISTER_MISSING;
+use shutdown::ShutdownFd;
+
+const END: u64 = !0;
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64, Error> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn write_u64<W: Write>(w: &mut W, value: u64) -> Result<(), Error> {
+    w.write_all(&value.to_le_bytes())
+}
+
+/// The source side of a migration, serving the pages of a region that is no longer modified.
+#[derive(Debug)]
+pub struct PostcopyServer<'a> {
+    region: &'a [u8],
+    page_size: usize,
+}
+
+impl<'a> PostcopyServer<'a> {
+    pub fn new(region: &'a [u8]) -> Self {
+        Self {
+            region,
+            page_size: PageSize::system().bytes(),
+        }
+    }
+
+    builder_methods!{
+        page_size: usize
+    }
+
+    /// Send the whole region over `stream`, answering the requests coming back on it first.
+    pub fn serve_unix(&self, stream: &UnixStream) -> Result<(), Error> {
+        let ret = self.serve(stream.try_clone()?, stream);
+        if ret.is_err() {
+            // Unblock the request reader left behind by serve()
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        ret
+    }
+
+    /// Send the whole region to `pages`, answering the requests read from `requests` first. Returns once
+    /// every page has been sent and the destination has closed `requests`.
+    ///
+    /// Requests are read on a separate thread. If sending fails, the error is returned right away and that
+    /// thread stops once its next read from `requests` returns.
+    pub fn serve<R: Read + Send + 'static, W: Write>(&self, requests: R, mut pages: W) -> Result<(), Error> {
+        let count = self.region.len().div_ceil(self.page_size);
+        write_u64(&mut pages, self.page_size as u64)?;
+        write_u64(&mut pages, count as u64)?;
+        let (tx, rx) = mpsc::channel();
+        let reader = thread::Builder::new()
+            .name("userfaultfd-postcopy".into())
+            .spawn(move || {
+                let mut requests = requests;
+                loop {
+                    let offset = match read_u64(&mut requests) {
+                        Ok(offset) => offset,
+                        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                        Err(e) => return Err(e)
+                    };
+                    if tx.send(offset).is_err() {
+                        return Ok(());
+                    }
+                }
+            })?;
+        let mut sent = vec![false; count];
+        let mut next = 0;
+        loop {
+            // Requested pages jump the queue of the background push
+            let (index, requested) = match rx.try_recv() {
+                Ok(offset) => (offset as usize / self.page_size, true),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {
+                    while next < count && sent[next] {
+                        next += 1;
+                    }
+                    if next == count {
+                        break;
+                    }
+                    (next, false)
+                }
+            };
+            if index >= count {
+                return Err(Error::new(ErrorKind::InvalidData, "page request outside of the region"));
+            }
+            if sent[index] {
+                continue;
+            }
+            sent[index] = true;
+            self.send_page(&mut pages, index)?;
+            if requested {
+                pages.flush()?;
+            }
+        }
+        write_u64(&mut pages, END)?;
+        pages.flush()?;
+        drop(rx);
+        reader.join().unwrap_or_else(|_| Err(Error::other("postcopy request reader panicked")))
+    }
+
+    fn send_page<W: Write>(&self, pages: &mut W, index: usize) -> Result<(), Error> {
+        let start = index * self.page_size;
+        let end = self.region.len().min(start + self.page_size);
+        write_u64(pages, start as u64)?;
+        pages.write_all(&self.region[start..end])?;
+        // The last page is padded, the destination always receives whole pages
+        if end - start < self.page_size {
+            pages.write_all(&vec![0; self.page_size - (end - start)])?;
+        }
+        Ok(())
+    }
+}
+
+/// The destination side of a migration.
+#[derive(Debug)]
+pub struct PostcopyClient {
+    handle: Handle,
+    start: usize,
+    len: usize,
+}
+
+impl PostcopyClient {
+    /// Register `region` with `handle` for missing faults. The handle must be non-blocking and the
+    /// region must be untouched, private or shared anonymous memory as large as the source region
+    /// rounded up to its page size.
+    pub fn new<T: Into<Range>>(handle: Handle, region: T) -> Result<Self, UffdError> {
+        let region = region.into();
+        let (start, len) = (region.start as usize, region.len);
+        handle.register(region, REGISTER_MISSING)?;
+        Ok(Self { handle, start, len })
+    }
+
+    /// Receive the region over `stream`, sending the page requests back on it. The writing half of the
+    /// stream is shut down once the migration is complete.
+    pub fn run_unix(self, stream: &UnixStream) -> Result<(), UffdError> {
+        self.run(stream, stream.try_clone()?)?;
+        stream.shutdown(Shutdown::Write)?;
+        Ok(())
+    }
+
+    /// Install the pages read from `pages`, sending requests for faulting pages to `requests`. Returns
+    /// once every page has been installed; the handle is then closed, which unregisters the region.
+    pub fn run<R: Read, W: Write + Send>(self, mut pages: R, requests: W) -> Result<(), UffdError> {
+        let page_size = read_u64(&mut pages)? as usize;
+        let count = read_u64(&mut pages)? as usize;
+        if page_size == 0 || !self.start.is_multiple_of(page_size) || count.checked_mul(page_size) != Some(self.len) {
+            return Err(UffdError::Io(Error::new(ErrorKind::InvalidData,
+                                                "source region does not match the destination region")));
+        }
+        let done = ShutdownFd::new()?;
+        thread::scope(|s| {
+            let faults = s.spawn(|| self.forward_faults(requests, &done, page_size));
+            let received = self.receive(&mut pages, page_size);
+            done.signal();
+            let forwarded = faults.join().unwrap_or_else(|_| {
+                Err(UffdError::Io(Error::other("postcopy fault forwarder panicked")))
+            });
+            received.and(forwarded)
+        })
+    }
+
+    fn receive<R: Read>(&self, pages: &mut R, page_size: usize) -> Result<(), UffdError> {
+        let mut page = vec![0; page_size];
+        loop {
+            let offset = read_u64(pages)?;
+            if offset == END {
+                return Ok(());
+            }
+            if offset as usize >= self.len || !(offset as usize).is_multiple_of(page_size) {
+                return Err(UffdError::Io(Error::new(ErrorKind::InvalidData, "page outside of the region")));
+            }
+            pages.read_exact(&mut page)?;
+            let dst = (self.start + offset as usize) as *mut u8;
+            match self.handle.copy(dst, page.as_mut_ptr(), page_size as u64, CopyMode::empty()) {
+                // The page was already installed, or the range was unmapped by the application
+                Ok(()) | Err(UffdError::PageExists) | Err(UffdError::MappingChanged) => {}
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    fn forward_faults<W: Write>(&self, mut requests: W, done: &ShutdownFd, page_size: usize)
+        -> Result<(), UffdError>
+    {
+        let mut buf = MessageBuffer::new(64);
+        while done.read_messages(&self.handle, &mut buf)? {
+            for msg in buf.drain() {
+                if let Message::Pagefault(fault) = msg {
+                    let offset = (fault.address as usize & !(page_size - 1)) - self.start;
+                    write_u64(&mut requests, offset as u64)?;
+                }
+            }
+            // The source may already have stopped reading once it sent everything
+            match requests.flush() {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == ErrorKind::BrokenPipe => {}
+                Err(e) => return Err(UffdError::Io(e))
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,9 @@ use sc::platform::nr::{USERFAULTFD, IOCTL, READ};
 use std::os::unix::io::RawFd;
 use std::os::raw::c_void;
 use std::io::{Error,ErrorKind};
+use UffdError;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
 
 pub mod defines {
     #![allow(non_upper_case_globals)]
@@ -11,16 +14,30 @@ pub mod defines {
     // This is necessary to work around a bug in bindgen with functional macros
     // https://github.com/servo/rust-bindgen/issues/753
     pub const UFFD_API: u64 = _T_UFFD_API as u64;
+    pub const USERFAULTFD_IOC_NEW: u64 = _T_USERFAULTFD_IOC_NEW as u64;
+    pub const PAGEMAP_SCAN: u64 = _T_PAGEMAP_SCAN as u64;
     pub const UFFDIO_API: u64 = _T_UFFDIO_API as u64;
     pub const UFFDIO_REGISTER: u64 = _T_UFFDIO_REGISTER as u64;
     pub const UFFDIO_UNREGISTER: u64 = _T_UFFDIO_UNREGISTER as u64;
     pub const UFFDIO_WAKE: u64 = _T_UFFDIO_WAKE as u64;
     pub const UFFDIO_COPY: u64 = _T_UFFDIO_COPY as u64;
     pub const UFFDIO_ZEROPAGE: u64 = _T_UFFDIO_ZEROPAGE as u64;
+    pub const UFFDIO_WRITEPROTECT: u64 = _T_UFFDIO_WRITEPROTECT as u64;
+    pub const UFFDIO_CONTINUE: u64 = _T_UFFDIO_CONTINUE as u64;
+    pub const UFFDIO_MOVE: u64 = _T_UFFDIO_MOVE as u64;
+    pub const UFFDIO_POISON: u64 = _T_UFFDIO_POISON as u64;
     pub const UFFDIO_REGISTER_MODE_MISSING: u64 = _T_UFFDIO_REGISTER_MODE_MISSING as u64;
     pub const UFFDIO_REGISTER_MODE_WP: u64 = _T_UFFDIO_REGISTER_MODE_WP as u64;
+    pub const UFFDIO_REGISTER_MODE_MINOR: u64 = _T_UFFDIO_REGISTER_MODE_MINOR as u64;
     pub const UFFDIO_COPY_MODE_DONTWAKE: u64 = _T_UFFDIO_COPY_MODE_DONTWAKE as u64;
     pub const UFFDIO_ZEROPAGE_MODE_DONTWAKE: u64 = _T_UFFDIO_ZEROPAGE_MODE_DONTWAKE as u64;
+    pub const UFFDIO_COPY_MODE_WP: u64 = _T_UFFDIO_COPY_MODE_WP as u64;
+    pub const UFFDIO_WRITEPROTECT_MODE_WP: u64 = _T_UFFDIO_WRITEPROTECT_MODE_WP as u64;
+    pub const UFFDIO_WRITEPROTECT_MODE_DONTWAKE: u64 = _T_UFFDIO_WRITEPROTECT_MODE_DONTWAKE as u64;
+    pub const UFFDIO_CONTINUE_MODE_DONTWAKE: u64 = _T_UFFDIO_CONTINUE_MODE_DONTWAKE as u64;
+    pub const UFFDIO_MOVE_MODE_DONTWAKE: u64 = _T_UFFDIO_MOVE_MODE_DONTWAKE as u64;
+    pub const UFFDIO_MOVE_MODE_ALLOW_SRC_HOLES: u64 = _T_UFFDIO_MOVE_MODE_ALLOW_SRC_HOLES as u64;
+    pub const UFFDIO_POISON_MODE_DONTWAKE: u64 = _T_UFFDIO_POISON_MODE_DONTWAKE as u64;
     pub const UFFD_API_IOCTLS: u64 = _T_UFFD_API_IOCTLS as u64;
     pub const UFFD_API_RANGE_IOCTLS: u64 = _T_UFFD_API_RANGE_IOCTLS as u64;
     pub const UFFD_API_RANGE_IOCTLS_BASIC: u64 = _T_UFFD_API_RANGE_IOCTLS_BASIC as u64;
@@ -35,8 +52,13 @@ impl From<super::Range> for defines::uffdio_range {
     }
 }
 
-pub fn userfaultfd(flags: usize) -> RawFd {
-    unsafe { syscall!(USERFAULTFD, flags) as i32 }
+pub fn userfaultfd(flags: usize) -> Result<RawFd, Error> {
+    unsafe { cvt(syscall!(USERFAULTFD, flags) as i64).map(|fd| fd as RawFd) }
+}
+/// Create a userfaultfd object through the `/dev/userfaultfd` device node (since Linux 6.1)
+pub fn userfaultfd_dev(flags: usize) -> Result<RawFd, Error> {
+    let dev = OpenOptions::new().read(true).write(true).open("/dev/userfaultfd")?;
+    ioctl(dev.as_raw_fd(), defines::USERFAULTFD_IOC_NEW, flags as *mut c_void).map(|fd| fd as RawFd)
 }
 fn ioctl(fd: RawFd, cmd: u64, arg: *mut c_void) -> Result<i64, Error> {
     unsafe { retry(||syscall!(IOCTL, fd, cmd, arg) as i64) }
@@ -59,64 +81,128 @@ fn retry<F: FnMut() -> i64>(mut f: F) -> Result<i64, Error> {
         }
     }
 }
-pub fn uffdio_api(fd: RawFd, t: &mut defines::uffdio_api) -> Result<(), Error> {
+pub fn uffdio_api(fd: RawFd, t: &mut defines::uffdio_api) -> Result<(), UffdError> {
     match ioctl(fd, defines::UFFDIO_API, t as *mut _ as *mut c_void) {
-        Err(e) => Err(e),
+        Err(e) => Err(UffdError::from_ioctl(e)),
         Ok(0) => Ok(()),
-        Ok(x) => panic!("Unexpected return value from UFFDIO_API ioctl: {}", x)
+        Ok(x) => Err(UffdError::UnexpectedReturn { ioctl: "UFFDIO_API", value: x })
     }
 }
-pub fn uffdio_register(fd: RawFd, mode: u64, range: defines::uffdio_range) -> Result<u64, Error> {
+pub fn uffdio_register(fd: RawFd, mode: u64, range: defines::uffdio_range) -> Result<u64, UffdError> {
     let mut t = defines::uffdio_register {
         range: range,
         mode: mode,
         ioctls: 0
     };
     match ioctl(fd, defines::UFFDIO_REGISTER, &mut t as *mut _ as *mut c_void) {
-        Err(e) => Err(e),
+        Err(e) => Err(UffdError::from_ioctl(e)),
         Ok(0) => Ok(t.ioctls),
-        Ok(x) => panic!("Unexpected return value from UFFDIO_REGISTER ioctl: {}", x)
+        Ok(x) => Err(UffdError::UnexpectedReturn { ioctl: "UFFDIO_REGISTER", value: x })
     }
 }
-pub fn uffdio_unregister(fd: RawFd, mut range: defines::uffdio_range) -> Result<(), Error> {
+pub fn uffdio_unregister(fd: RawFd, mut range: defines::uffdio_range) -> Result<(), UffdError> {
     match ioctl(fd, defines::UFFDIO_UNREGISTER, &mut range as *mut _ as *mut c_void) {
-        Err(e) => Err(e),
+        Err(e) => Err(UffdError::from_ioctl(e)),
         Ok(0) => Ok(()),
-        Ok(x) => panic!("Unexpected return value from UFFDIO_UNREGISTER ioctl: {}", x)
+        Ok(x) => Err(UffdError::UnexpectedReturn { ioctl: "UFFDIO_UNREGISTER", value: x })
     }
 }
-pub fn uffdio_copy(fd: RawFd, mut copy: defines::uffdio_copy) -> Result<(), Error> {
+pub fn uffdio_copy(fd: RawFd, mut copy: defines::uffdio_copy) -> Result<(), UffdError> {
     loop {
+        copy.copy = 0;
         match ioctl(fd, defines::UFFDIO_COPY, &mut copy as *mut _ as *mut c_void) {
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+            // EAGAIN with a positive count means the copy was interrupted part way and the rest can be
+            // retried. Without progress an event is pending and has to be read first
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock && copy.copy > 0 => {
                 copy.dst += copy.copy as u64;
                 copy.src += copy.copy as u64;
                 copy.len -= copy.copy as u64;
             }
-            Err(e) => return Err(e),
+            Err(e) => return Err(UffdError::from_ioctl(e)),
             Ok(0) => return Ok(()),
-            Ok(x) => panic!("Unexpected return value from UFFDIO_COPY ioctl: {}", x)
+            Ok(x) => return Err(UffdError::UnexpectedReturn { ioctl: "UFFDIO_COPY", value: x })
         }
     }
 }
-pub fn uffdio_zeropage(fd: RawFd, mut zeropage: defines::uffdio_zeropage) -> Result<(), Error> {
-    println!("{:?}", zeropage);
+pub fn uffdio_zeropage(fd: RawFd, mut zeropage: defines::uffdio_zeropage) -> Result<(), UffdError> {
     loop {
+        zeropage.zeropage = 0;
         match ioctl(fd, defines::UFFDIO_ZEROPAGE, &mut zeropage as *mut _ as *mut c_void) {
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock && zeropage.zeropage > 0 => {
                 zeropage.range.start += zeropage.zeropage as u64;
                 zeropage.range.len -= zeropage.zeropage as u64;
             }
-            Err(e) => return Err(e),
+            Err(e) => return Err(UffdError::from_ioctl(e)),
             Ok(0) => return Ok(()),
-            Ok(x) => panic!("Unexpected return value from UFFDIO_COPY ioctl: {}", x)
+            Ok(x) => return Err(UffdError::UnexpectedReturn { ioctl: "UFFDIO_ZEROPAGE", value: x })
         }
     }
 }
-pub fn uffdio_wake(fd: RawFd, mut range: defines::uffdio_range) -> Result<(), Error> {
+pub fn uffdio_wake(fd: RawFd, mut range: defines::uffdio_range) -> Result<(), UffdError> {
     match ioctl(fd, defines::UFFDIO_WAKE, &mut range as *mut _ as *mut c_void) {
-        Err(e) => Err(e),
+        Err(e) => Err(UffdError::from_ioctl(e)),
+        Ok(0) => Ok(()),
+        Ok(x) => Err(UffdError::UnexpectedReturn { ioctl: "UFFDIO_WAKE", value: x })
+    }
+}
+pub fn uffdio_writeprotect(fd: RawFd, mut writeprotect: defines::uffdio_writeprotect) -> Result<(), UffdError> {
+    match ioctl(fd, defines::UFFDIO_WRITEPROTECT, &mut writeprotect as *mut _ as *mut c_void) {
+        Err(e) => Err(UffdError::from_ioctl(e)),
         Ok(0) => Ok(()),
-        Ok(x) => panic!("Unexpected return value from UFFDIO_WAKE ioctl: {}", x)
+        Ok(x) => Err(UffdError::UnexpectedReturn { ioctl: "UFFDIO_WRITEPROTECT", value: x })
+    }
+}
+pub fn uffdio_continue(fd: RawFd, mut cont: defines::uffdio_continue) -> Result<(), UffdError> {
+    loop {
+        cont.mapped = 0;
+        match ioctl(fd, defines::UFFDIO_CONTINUE, &mut cont as *mut _ as *mut c_void) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock && cont.mapped > 0 => {
+                cont.range.start += cont.mapped as u64;
+                cont.range.len -= cont.mapped as u64;
+            }
+            Err(e) => return Err(UffdError::from_ioctl(e)),
+            Ok(0) => return Ok(()),
+            Ok(x) => return Err(UffdError::UnexpectedReturn { ioctl: "UFFDIO_CONTINUE", value: x })
+        }
     }
 }
+pub fn uffdio_move(fd: RawFd, mut mv: defines::uffdio_move) -> Result<(), (u64, UffdError)> {
+    let mut moved = 0;
+    loop {
+        mv.move_ = 0;
+        match ioctl(fd, defines::UFFDIO_MOVE, &mut mv as *mut _ as *mut c_void) {
+            // EAGAIN with a positive count means the move was interrupted part way and the rest can be
+            // retried. Without progress an event is pending and has to be read first
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock && mv.move_ > 0 => {
+                moved += mv.move_ as u64;
+                mv.dst += mv.move_ as u64;
+                mv.src += mv.move_ as u64;
+                mv.len -= mv.move_ as u64;
+            }
+            Err(e) => return Err((moved, UffdError::from_ioctl(e))),
+            Ok(0) => return Ok(()),
+            Ok(x) => return Err((moved, UffdError::UnexpectedReturn { ioctl: "UFFDIO_MOVE", value: x }))
+        }
+    }
+}
+pub fn uffdio_poison(fd: RawFd, mut poison: defines::uffdio_poison) -> Result<(), UffdError> {
+    loop {
+        poison.updated = 0;
+        match ioctl(fd, defines::UFFDIO_POISON, &mut poison as *mut _ as *mut c_void) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock && poison.updated > 0 => {
+                poison.range.start += poison.updated as u64;
+                poison.range.len -= poison.updated as u64;
+            }
+            Err(e) => return Err(UffdError::from_ioctl(e)),
+            Ok(0) => return Ok(()),
+            Ok(x) => return Err(UffdError::UnexpectedReturn { ioctl: "UFFDIO_POISON", value: x })
+        }
+    }
+}
+/// Scan the page table entries of a range with `PAGEMAP_SCAN` on a `/proc/<pid>/pagemap` file (since
+/// Linux 6.7). Returns the number of regions written to `arg.vec`.
+pub fn pagemap_scan(fd: RawFd, arg: &mut defines::pm_scan_arg) -> Result<usize, UffdError> {
+    ioctl(fd, defines::PAGEMAP_SCAN, arg as *mut _ as *mut c_void)
+        .map(|n| n as usize)
+        .map_err(UffdError::from_ioctl)
+}
@@ -0,0 +1,140 @@
+use libc;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Error;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr;
+use std::slice;
+
+use {Handle, Ioctls, PageSize, Range, RegisterMode, UffdError};
+use transfer::RegionInfo;
+
+/// The memory backing a `Region`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Private anonymous memory.
+    Anonymous,
+    /// Shared memory from a `memfd_create(2)` file, which can also be mapped elsewhere through
+    /// `Region::file()`. Registering it needs the `shmem` or `minor_shmem` option on the `Builder`.
+    Memfd,
+    /// Shared memory from a `memfd_create(2)` file on hugetlbfs, with huge pages of the given size.
+    /// Registering it needs the `hugetlbfs` or `minor_hugetlbfs` option on the `Builder`.
+    Hugetlb(PageSize),
+}
+
+/// Memory mapped and registered through a `Handle`, created with `Handle::create_region()`.
+///
+/// The region is unregistered and unmapped when it is dropped, and cannot outlive its handle.
+#[derive(Debug)]
+pub struct Region<'a> {
+    handle: &'a Handle,
+    start: *mut u8,
+    len: usize,
+    page_size: PageSize,
+    mode: RegisterMode,
+    ioctls: Ioctls,
+    file: Option<File>,
+}
+
+unsafe impl<'a> Send for Region<'a> {}
+unsafe impl<'a> Sync for Region<'a> {}
+
+impl<'a> Region<'a> {
+    pub(crate) fn new(handle: &'a Handle, len: usize, backing: Backing, mode: RegisterMode)
+        -> Result<Region<'a>, UffdError>
+    {
+        let name = CStr::from_bytes_with_nul(b"userfaultfd-region\0").unwrap();
+        let (page_size, file) = match backing {
+            Backing::Anonymous => (PageSize::system(), None),
+            Backing::Memfd => (PageSize::system(), Some(memfd(name, 0)?)),
+            Backing::Hugetlb(size) => {
+                let flags = libc::MFD_HUGETLB | (size.bytes().trailing_zeros() << libc::MFD_HUGE_SHIFT);
+                (size, Some(memfd(name, flags)?))
+            }
+        };
+        let len = page_size.align_up(len);
+        let (flags, fd) = match file {
+            Some(ref file) => {
+                file.set_len(len as u64)?;
+                (libc::MAP_SHARED, file.as_raw_fd())
+            }
+            None => (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE, -1)
+        };
+        let start = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, flags, fd, 0) };
+        if start == libc::MAP_FAILED {
+            return Err(UffdError::Io(Error::last_os_error()));
+        }
+        let start = start as *mut u8;
+        match handle.register(Range { start, len }, mode) {
+            Ok(ioctls) => Ok(Region { handle, start, len, page_size, mode, ioctls, file }),
+            Err(e) => {
+                unsafe { libc::munmap(start as *mut libc::c_void, len); }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn handle(&self) -> &'a Handle {
+        self.handle
+    }
+
+    pub fn range(&self) -> Range {
+        Range { start: self.start, len: self.len }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.start
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn page_size(&self) -> PageSize {
+        self.page_size
+    }
+
+    /// The operations available on the region, as returned by `register()`.
+    pub fn ioctls(&self) -> Ioctls {
+        self.ioctls
+    }
+
+    /// The description of the region to send along with its handle with `transfer::send_handle()`.
+    pub fn info(&self) -> RegionInfo {
+        RegionInfo { start: self.start as usize, len: self.len, mode: self.mode, ioctls: self.ioctls }
+    }
+
+    /// The memfd backing the region, for `Backing::Memfd` and `Backing::Hugetlb`.
+    pub fn file(&self) -> Option<&File> {
+        self.file.as_ref()
+    }
+
+    /// The contents of the region. Touching pages that have not been resolved yet blocks until the
+    /// corresponding faults are handled.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.start, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.start, self.len) }
+    }
+}
+
+impl<'a> Drop for Region<'a> {
+    fn drop(&mut self) {
+        let _ = self.handle.unregister(self.range());
+        unsafe { libc::munmap(self.start as *mut libc::c_void, self.len); }
+    }
+}
+
+fn memfd(name: &CStr, flags: libc::c_uint) -> Result<File, Error> {
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | flags) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
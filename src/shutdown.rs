@@ -0,0 +1,63 @@
+use libc;
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+use {Handle, MessageBuffer, UffdError};
+
+/// An eventfd telling the threads waiting on handles to stop.
+#[derive(Debug)]
+pub(crate) struct ShutdownFd(OwnedFd);
+
+impl ShutdownFd {
+    pub(crate) fn new() -> Result<ShutdownFd, Error> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(ShutdownFd(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    /// Wake up the threads waiting on the eventfd. It stays readable, so later waits return at once.
+    pub(crate) fn signal(&self) {
+        let one: u64 = 1;
+        unsafe { libc::write(self.0.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8); }
+    }
+
+    pub(crate) fn pollfd(&self) -> libc::pollfd {
+        libc::pollfd { fd: self.0.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+    }
+
+    /// Wait for messages on `handle` and read as many as fit in `buf`. Returns false once signalled.
+    pub(crate) fn read_messages(&self, handle: &Handle, buf: &mut MessageBuffer) -> Result<bool, UffdError> {
+        loop {
+            let mut fds = [self.pollfd(), libc::pollfd { fd: handle.as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+            if !poll(&mut fds)? {
+                return Ok(false);
+            }
+            match handle.read_messages(buf) {
+                Ok(_) => return Ok(true),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(UffdError::Io(e))
+            }
+        }
+    }
+}
+
+/// Wait until one of `fds` is ready. The first entry must come from `ShutdownFd::pollfd()`; returns false
+/// once it has been signalled.
+pub(crate) fn poll(fds: &mut [libc::pollfd]) -> Result<bool, UffdError> {
+    wait(fds, -1)
+}
+
+/// Like `poll()`, but give up after `timeout` milliseconds.
+pub(crate) fn wait(fds: &mut [libc::pollfd], timeout: libc::c_int) -> Result<bool, UffdError> {
+    loop {
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } >= 0 {
+            return Ok(fds[0].revents == 0);
+        }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(UffdError::Io(e));
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use libc;
+use std::io::Error;
+use std::mem;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, Once, OnceLock};
+use std::thread;
+
+use Handle;
+use Range;
+
+/// The maximum number of ranges that can be routed at the same time.
+pub const MAX_SIGBUS_RANGES: usize = 64;
+
+type Callback = Box<dyn Fn(&Handle, *mut u8) -> bool + Send + Sync>;
+
+struct Entry {
+    start: usize,
+    end: usize,
+    handle: Arc<Handle>,
+    callback: Callback,
+}
+
+/// The handler cannot take locks or free memory, so ranges live in a fixed array of slots. `active` counts
+/// the handlers currently looking at the slot, and an entry is only freed once it has dropped to zero
+/// after the entry was unpublished.
+struct Slot {
+    entry: AtomicPtr<Entry>,
+    active: AtomicUsize,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Slot = Slot { entry: AtomicPtr::new(ptr::null_mut()), active: AtomicUsize::new(0) };
+
+static SLOTS: [Slot; MAX_SIGBUS_RANGES] = [EMPTY; MAX_SIGBUS_RANGES];
+static INSTALL: Once = Once::new();
+static PREVIOUS: OnceLock<Result<libc::sigaction, i32>> = OnceLock::new();
+
+/// A range whose `SIGBUS` faults are routed to a callback, see `handle_sigbus()`.
+///
+/// Dropping the value stops routing faults for the range; later accesses fall through to the `SIGBUS`
+/// handler that was installed before.
+#[derive(Debug)]
+pub struct SigbusRange {
+    slot: usize,
+}
+
+impl Drop for SigbusRange {
+    fn drop(&mut self) {
+        let slot = &SLOTS[self.slot];
+        let entry = slot.entry.swap(ptr::null_mut(), Ordering::SeqCst);
+        // A handler that still sees the entry has announced itself in `active` before loading it
+        while slot.active.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        drop(unsafe { Box::from_raw(entry) });
+    }
+}
+
+/// Route `SIGBUS` faults on `range` to `callback`, running on the faulting thread.
+///
+/// The range must be registered with `handle`, which must have been created with the `sigbus` option
+/// enabled on the `Builder`. In that mode the kernel never queues `Pagefault` messages: an access to a
+/// missing page raises `SIGBUS` on the faulting thread instead. The first call installs a process wide
+/// `SIGBUS` handler that looks up the faulting address and calls `callback` with the handle and the
+/// faulting address. The callback can populate the page, for example with `Handle::copy()` or
+/// `Handle::zeropage()`, and return true to retry the access. If it returns false, or the address is not
+/// in any routed range, the signal is passed on to the previously installed handler, which by default
+/// terminates the process.
+///
+/// The callback runs in signal context: it must not panic, and should restrict itself to
+/// async-signal-safe operations such as the ioctls exposed by `Handle`.
+///
+/// At most `MAX_SIGBUS_RANGES` ranges can be routed at the same time, further calls fail with an error
+/// until one of the ranges is dropped.
+pub fn handle_sigbus<T, F>(handle: Arc<Handle>, range: T, callback: F) -> Result<SigbusRange, Error>
+    where T: Into<Range>, F: Fn(&Handle, *mut u8) -> bool + Send + Sync + 'static
+{
+    INSTALL.call_once(|| {
+        let _ = PREVIOUS.set(unsafe { install_handler() });
+    });
+    if let Some(&Err(errno)) = PREVIOUS.get() {
+        return Err(Error::from_raw_os_error(errno));
+    }
+
+    let range = range.into();
+    let entry = Box::into_raw(Box::new(Entry {
+        start: range.start as usize,
+        end: range.start as usize + range.len,
+        handle,
+        callback: Box::new(callback),
+    }));
+    for (i, slot) in SLOTS.iter().enumerate() {
+        if slot.entry.compare_exchange(ptr::null_mut(), entry, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            return Ok(SigbusRange { slot: i });
+        }
+    }
+    drop(unsafe { Box::from_raw(entry) });
+    Err(Error::other("too many SIGBUS ranges"))
+}
+
+unsafe fn install_handler() -> Result<libc::sigaction, i32> {
+    let mut sa: libc::sigaction = mem::zeroed();
+    sa.sa_sigaction = sigbus_handler as *const () as usize;
+    sa.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    libc::sigemptyset(&mut sa.sa_mask);
+    let mut old: libc::sigaction = mem::zeroed();
+    if libc::sigaction(libc::SIGBUS, &sa, &mut old) != 0 {
+        return Err(Error::last_os_error().raw_os_error().unwrap_or(0));
+    }
+    Ok(old)
+}
+
+extern "C" fn sigbus_handler(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+    for slot in SLOTS.iter() {
+        slot.active.fetch_add(1, Ordering::SeqCst);
+        let entry = slot.entry.load(Ordering::SeqCst);
+        let resolved = match unsafe { entry.as_ref() } {
+            Some(e) if addr >= e.start && addr < e.end => Some((e.callback)(&e.handle, addr as *mut u8)),
+            _ => None
+        };
+        slot.active.fetch_sub(1, Ordering::SeqCst);
+        match resolved {
+            Some(true) => return,
+            Some(false) => break,
+            None => {}
+        }
+    }
+    unsafe { chain(sig, info, ctx) }
+}
+
+unsafe fn chain(sig: c_int, info: *mut libc::siginfo_t, ctx: *mut c_void) {
+    match PREVIOUS.get() {
+        Some(Ok(old)) if old.sa_sigaction != libc::SIG_DFL && old.sa_sigaction != libc::SIG_IGN => {
+            if old.sa_flags & libc::SA_SIGINFO != 0 {
+                let f: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) = mem::transmute(old.sa_sigaction);
+                f(sig, info, ctx);
+            } else {
+                let f: extern "C" fn(c_int) = mem::transmute(old.sa_sigaction);
+                f(sig);
+            }
+        }
+        _ => {
+            // Restore the default action, the access faults again on return and terminates the process
+            let mut dfl: libc::sigaction = mem::zeroed();
+            dfl.sa_sigaction = libc::SIG_DFL;
+            libc::sigaction(sig, &dfl, ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use libc;
+use std::io::Error;
+use std::ptr;
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+
+use {Handle, Message, MessageBuffer, PageSize, Range, UffdError, WriteprotectMode};
+use {PAGEFAULT_FLAG_WP, REGISTER_WP};
+use shutdown::ShutdownFd;
+
+/// A point-in-time copy of a memory region that stays readable while the region is being written.
+///
+/// Taking a snapshot write-protects the region. The first write to each page is caught by a handler
+/// thread, which saves the original contents of the page before lifting the protection, so only the
+/// pages written since the snapshot are ever copied. Reads of pages that have not been written go to the
+/// region itself.
+///
+/// The handle must be created with `FEATURE_PAGEFAULT_FLAG_WP`, and is switched to non-blocking mode. Only
+/// populated pages can be write-protected without `FEATURE_WP_UNPOPULATED`, so the whole region is
+/// populated for writing with `MADV_POPULATE_WRITE` `(since Linux 5.14)` when the snapshot is taken.
+#[derive(Debug)]
+pub struct Snapshot {
+    state: Arc<State>,
+    thread: Option<JoinHandle<Result<(), UffdError>>>,
+}
+
+#[derive(Debug)]
+struct State {
+    handle: Handle,
+    start: usize,
+    len: usize,
+    page_size: usize,
+    pages: RwLock<Pages>,
+    shutdown: ShutdownFd,
+}
+
+#[derive(Debug)]
+struct Pages {
+    saved: Vec<bool>,
+    store: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Register `region` with `REGISTER_WP`, write-protect it and start saving the pages written to.
+    pub fn take<T: Into<Range>>(handle: Handle, region: T) -> Result<Snapshot, UffdError> {
+        let region = region.into();
+        let (start, len) = (region.start as usize, region.len);
+        let page_size = PageSize::of(region.start)?.bytes();
+        let shutdown = ShutdownFd::new()?;
+        handle.set_non_blocking()?;
+        // Write-protecting a page that is not populated does nothing, writes to it would go unnoticed
+        if unsafe { libc::madvise(start as *mut libc::c_void, len, libc::MADV_POPULATE_WRITE) } != 0 {
+            return Err(UffdError::Io(Error::last_os_error()));
+        }
+        handle.register(Range { start: start as *mut u8, len }, REGISTER_WP)?;
+        let state = Arc::new(State {
+            handle,
+            start,
+            len,
+            page_size,
+            pages: RwLock::new(Pages { saved: vec![false; len / page_size], store: vec![0; len] }),
+            shutdown,
+        });
+        let protect = state.handle.write_protect(state.range(), true, WriteprotectMode::empty());
+        if let Err(e) = protect {
+            let _ = state.handle.unregister(state.range());
+            return Err(e);
+        }
+        let worker = state.clone();
+        let thread = thread::Builder::new()
+            .name("userfaultfd-snapshot".into())
+            .spawn(move || worker.run());
+        match thread {
+            Ok(thread) => Ok(Snapshot { state, thread: Some(thread) }),
+            Err(e) => {
+                let _ = state.release();
+                Err(UffdError::Io(e))
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.len == 0
+    }
+
+    /// The number of pages written since the snapshot was taken, whose original contents were saved.
+    pub fn saved_pages(&self) -> usize {
+        self.state.pages.read().unwrap().saved.iter().filter(|&&saved| saved).count()
+    }
+
+    /// Copy the snapshot contents starting at `offset` into `buf`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the read reaches past the end of the snapshot.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        assert!(offset <= self.state.len && buf.len() <= self.state.len - offset, "read out of bounds");
+        let page_size = self.state.page_size;
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done;
+            let n = (page_size - at % page_size).min(buf.len() - done);
+            // The handler saves a page under the write lock before unprotecting it, so a page that is not
+            // saved yet cannot change while the read lock is held
+            let pages = self.state.pages.read().unwrap();
+            let src = if pages.saved[at / page_size] {
+                pages.store[at..].as_ptr()
+            } else {
+                (self.state.start + at) as *const u8
+            };
+            unsafe { ptr::copy_nonoverlapping(src, buf[done..].as_mut_ptr(), n); }
+            done += n;
+        }
+    }
+
+    /// Stop tracking writes, lift the remaining protection and unregister the region.
+    pub fn release(mut self) -> Result<(), UffdError> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<(), UffdError> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(())
+        };
+        self.state.shutdown.signal();
+        let ret = thread.join().unwrap_or_else(|_| {
+            Err(UffdError::Io(Error::other("userfaultfd snapshot thread panicked")))
+        });
+        ret.and(self.state.release())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+impl State {
+    fn range(&self) -> Range {
+        Range { start: self.start as *mut u8, len: self.len }
+    }
+
+    /// Unprotect the whole region, which also wakes the writers whose faults were never handled
+    fn release(&self) -> Result<(), UffdError> {
+        self.handle.write_protect(self.range(), false, WriteprotectMode::empty())
+            .and(self.handle.unregister(self.range()))
+    }
+
+    fn run(&self) -> Result<(), UffdError> {
+        let mut buf = MessageBuffer::new(64);
+        while self.shutdown.read_messages(&self.handle, &mut buf)? {
+            for msg in buf.drain() {
+                match msg {
+                    Message::Pagefault(ref fault) if fault.pagefault_flags().contains(PAGEFAULT_FLAG_WP) => {
+                        let addr = fault.address as usize & !(self.page_size - 1);
+                        self.save(addr - self.start);
+                        let page = Range { start: addr as *mut u8, len: self.page_size };
+                        match self.handle.write_protect(page, false, WriteprotectMode::empty()) {
+                            Ok(()) | Err(UffdError::MappingChanged) => {}
+                            Err(UffdError::ProcessExited) => return Ok(()),
+                            Err(e) => return Err(e)
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self, offset: usize) {
+        let mut pages = self.pages.write().unwrap();
+        let index = offset / self.page_size;
+        if !pages.saved[index] {
+            let src = (self.start + offset) as *const u8;
+            unsafe { ptr::copy_nonoverlapping(src, pages.store[offset..].as_mut_ptr(), self.page_size); }
+            pages.saved[index] = true;
+        }
+    }
+}
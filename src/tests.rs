@@ -23,3 +23,694 @@ use std::os::unix::io::{AsRawFd,FromRawFd,IntoRawFd,RawFd};
 //    let res = raw_interface::uffdio_api(fd.as_raw_fd(), &mut t2);
 //    println!("res: {:?} t2: {:?}", res, t2);
 //}
+
+/// Private anonymous memory mapped without a handle, unmapped when dropped. Used for staging buffers and
+/// for the APIs that register memory themselves, such as `Snapshot`, `DirtyTracker` and `PostcopyClient`;
+/// tests that register memory directly use `Handle::create_region()` instead.
+struct Mapping {
+    start: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(len: usize) -> Mapping {
+        use libc;
+        use std::ptr;
+
+        let start = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        };
+        assert!(start != libc::MAP_FAILED);
+        Mapping { start: start as *mut u8, len }
+    }
+
+    fn range(&self) -> ::Range {
+        ::Range { start: self.start, len: self.len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { ::std::slice::from_raw_parts(self.start, self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { ::std::slice::from_raw_parts_mut(self.start, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { ::libc::munmap(self.start as *mut ::libc::c_void, self.len); }
+    }
+}
+
+#[test]
+fn probe_reports_basic_ioctls() {
+    let (_, ioctls) = ::probe().unwrap();
+    assert!(ioctls.contains(::IOCTL_API | ::IOCTL_REGISTER | ::IOCTL_UNREGISTER));
+}
+
+#[test]
+fn device_backend_resolves_faults() {
+    use libc;
+    use std::thread;
+
+    // Access is governed by the permissions of the device node, which may not be ours to open
+    let path = b"/dev/userfaultfd\0";
+    if unsafe { libc::access(path.as_ptr() as *const libc::c_char, libc::R_OK | libc::W_OK) } != 0 {
+        return;
+    }
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().backend(::Backend::Device).create().unwrap();
+    let region = handle.create_region(page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    let addr = region.as_ptr() as usize;
+    let reader = thread::spawn(move || unsafe { *(addr as *const u8) });
+    match handle.read_message().unwrap() {
+        ::Message::Pagefault(fault) => {
+            let page = ::Range { start: fault.address as *mut u8, len: page_size };
+            handle.zeropage(page, ::ZeropageMode::empty()).unwrap();
+        }
+        other => panic!("unexpected message {:?}", other)
+    }
+    assert_eq!(reader.join().unwrap(), 0);
+}
+
+#[test]
+fn user_mode_only_resolves_user_faults() {
+    use std::thread;
+
+    let page_size = ::PageSize::system().bytes();
+    let handle = match Builder::new().user_mode_only(true).create() {
+        Ok((handle, _)) => handle,
+        // Kernels before 5.11
+        Err(::UffdError::UserModeOnlyUnsupported) => return,
+        Err(e) => panic!("{}", e)
+    };
+    let region = handle.create_region(page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    let addr = region.as_ptr() as usize;
+    let reader = thread::spawn(move || unsafe { *(addr as *const u8) });
+    match handle.read_message().unwrap() {
+        ::Message::Pagefault(fault) => {
+            let page = ::Range { start: fault.address as *mut u8, len: page_size };
+            handle.zeropage(page, ::ZeropageMode::empty()).unwrap();
+        }
+        other => panic!("unexpected message {:?}", other)
+    }
+    assert_eq!(reader.join().unwrap(), 0);
+}
+
+#[test]
+fn unknown_event_is_preserved() {
+    let m = raw_interface::defines::uffd_msg { event: 0x7f, ..Default::default() };
+    match ::Message::from_raw(m) {
+        ::Message::Unknown { event, raw } => {
+            assert_eq!(event, 0x7f);
+            assert_eq!(raw[0], 0x7f);
+        }
+        other => panic!("unexpected message {:?}", other)
+    }
+}
+
+#[test]
+fn monitor_fills_missing_pages() {
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().non_block(true).create().unwrap();
+    let monitor = ::MonitorBuilder::new().spawn(handle, move |addr: usize, page: &mut [u8]| {
+        for b in page.iter_mut() {
+            *b = (addr / page_size) as u8;
+        }
+        Ok(())
+    }).unwrap();
+    let region = monitor.handle().create_region(16 * page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    for i in 0..16 {
+        let addr = region.as_ptr() as usize + i * page_size;
+        assert_eq!(region.as_slice()[i * page_size + 7], (addr / page_size) as u8);
+    }
+    drop(region);
+    monitor.shutdown().unwrap();
+}
+
+#[test]
+fn lazy_file_region_reads_through_transform() {
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    let page_size = ::PageSize::system().bytes();
+    let path = ::std::env::temp_dir().join(format!("userfaultfd-lazy-{}", ::std::process::id()));
+    File::create(&path).unwrap().write_all(&vec![1; page_size + 100]).unwrap();
+    let source = ::FilePageSource::new(File::open(&path).unwrap(), 0).transform(|_, page: &mut [u8]| {
+        for b in page.iter_mut() {
+            *b += 1;
+        }
+        Ok(())
+    });
+    let region = ::LazyFileRegion::new(source, page_size + 100).unwrap();
+    fs::remove_file(&path).unwrap();
+    let data = region.as_slice();
+    assert_eq!(region.len(), 2 * page_size);
+    assert_eq!((data[0], data[page_size + 99], data[page_size + 100]), (2, 2, 1));
+}
+
+#[test]
+fn postcopy_migrates_region() {
+    use postcopy::{PostcopyClient, PostcopyServer};
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    let page_size = ::PageSize::system().bytes();
+    let source: Vec<u8> = (0..64 * page_size).map(|i| (i / page_size) as u8).collect();
+    let dst = Mapping::new(source.len());
+    let (handle, _) = Builder::new().non_block(true).create().unwrap();
+    let client = PostcopyClient::new(handle, dst.range()).unwrap();
+    let (a, b) = UnixStream::pair().unwrap();
+    let server = thread::spawn(move || PostcopyServer::new(&source).serve_unix(&a));
+    let client = thread::spawn(move || client.run_unix(&b));
+    // Touch the end of the region first so that at least some pages are requested rather than pushed
+    for i in (0..64).rev() {
+        assert_eq!(dst.as_slice()[i * page_size + 1], i as u8);
+    }
+    client.join().unwrap().unwrap();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn postcopy_server_rejects_bad_request() {
+    use postcopy::PostcopyServer;
+    use std::io::{ErrorKind, Write};
+    use std::os::unix::net::UnixStream;
+
+    let source = vec![0u8; 4096 * ::PageSize::system().bytes()];
+    let (a, mut b) = UnixStream::pair().unwrap();
+    b.write_all(&(!0u64 - 1).to_le_bytes()).unwrap();
+    // The destination keeps its end open, the request reader must still not keep the server waiting
+    let mut pages = Vec::new();
+    let err = PostcopyServer::new(&source).serve(a, &mut pages).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    drop(b);
+}
+
+#[test]
+fn snapshot_keeps_original_pages() {
+    let page_size = ::PageSize::system().bytes();
+    let mut mapping = Mapping::new(8 * page_size);
+    for b in mapping.as_mut_slice().iter_mut() {
+        *b = 1;
+    }
+    let (handle, _) = Builder::new().non_block(true).features(::FEATURE_PAGEFAULT_FLAG_WP).create().unwrap();
+    let snapshot = ::Snapshot::take(handle, mapping.range()).unwrap();
+    let region = mapping.as_mut_slice();
+    region[page_size + 10] = 2;
+    region[3 * page_size] = 3;
+    let mut copy = vec![0; region.len()];
+    snapshot.read(0, &mut copy);
+    assert!(copy.iter().all(|&b| b == 1));
+    assert_eq!((region[page_size + 10], region[3 * page_size]), (2, 3));
+    assert_eq!(snapshot.saved_pages(), 2);
+    snapshot.release().unwrap();
+    region[5 * page_size] = 4;
+}
+
+#[test]
+fn dirty_tracker_reports_written_pages() {
+    let page_size = ::PageSize::system().bytes();
+    let mut mapping = Mapping::new(16 * page_size);
+    let (handle, _) = Builder::new().wp_async(true).wp_unpopulated(true).create().unwrap();
+    let tracker = ::DirtyTracker::new(handle, mapping.range()).unwrap();
+    let mem = mapping.start;
+    let region = mapping.as_mut_slice();
+    region[2 * page_size] = 1;
+    region[3 * page_size + 5] = 1;
+    region[9 * page_size] = 1;
+    let expected = vec![
+        ::Range { start: unsafe { mem.add(2 * page_size) }, len: 2 * page_size },
+        ::Range { start: unsafe { mem.add(9 * page_size) }, len: page_size },
+    ];
+    assert_eq!(tracker.dirty().unwrap(), expected);
+    assert_eq!(tracker.take_dirty().unwrap(), expected);
+    assert_eq!(tracker.dirty().unwrap(), vec![]);
+    region[9 * page_size] = 2;
+    assert_eq!(tracker.take_dirty().unwrap(), vec![expected[1]]);
+}
+
+#[test]
+fn fault_range_is_page_aligned() {
+    let page_size = ::PageSize::system();
+    let page = vec![0u8; 3 * page_size.bytes()];
+    let addr = page_size.align_up(page.as_ptr() as usize) + 123;
+    let msg = ::PagefaultMessage { _event: 0, _res1: 0, _res2: 0, _res3: 0, flags: 0, address: addr as u64, ptid: 0, _pad: 0 };
+    assert_eq!(::PageSize::of(addr as *const u8).unwrap(), page_size);
+    let range = ::Range::from_fault(&msg, page_size);
+    assert_eq!(range.start as usize, addr - 123);
+    assert_eq!(range.len, page_size.bytes());
+}
+
+#[test]
+fn region_is_mapped_and_registered() {
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().shmem(true).create().unwrap();
+    let region = handle.create_region(3 * page_size + 1, ::Backing::Memfd, ::REGISTER_MISSING).unwrap();
+    assert_eq!(region.len(), 4 * page_size);
+    assert!(region.ioctls().contains(::IOCTL_COPY | ::IOCTL_WAKE));
+    assert!(region.file().is_some());
+}
+
+#[test]
+fn range_constructors_validate() {
+    use {PageSize, Range, RangeError};
+
+    let page_size = PageSize::new(4096).unwrap();
+    let at = |addr: usize| addr as *mut u8;
+    assert_eq!(Range::new_aligned(at(0x1000), 0x2000, page_size).unwrap(), Range { start: at(0x1000), len: 0x2000 });
+    assert_eq!(Range::new_aligned(at(0x1001), 0x1000, page_size),
+               Err(RangeError::UnalignedStart { start: 0x1001, page_size }));
+    assert_eq!(Range::new_aligned(at(0x1000), 0x1001, page_size),
+               Err(RangeError::UnalignedLen { len: 0x1001, page_size }));
+    assert_eq!(Range::new_aligned(at(0x1000), 0, page_size), Err(RangeError::Empty));
+
+    let range = Range::covering(at(0x1ff0), 0x20, page_size).unwrap();
+    assert_eq!(range, Range { start: at(0x1000), len: 0x2000 });
+    assert_eq!(range.pages(page_size).unwrap().map(|p| p.start as usize).collect::<Vec<_>>(), vec![0x1000, 0x2000]);
+    let top = Range { start: at(!0 - 0x10), len: 0x8 };
+    assert_eq!(top.end(), Ok(at(!0 - 0x8)));
+    assert_eq!(top.pages(page_size).err(), Some(RangeError::Overflow { start: !0 - 0x10, len: 0x8 }));
+    assert_eq!(Range { start: at(0x1000), len: !0 }.end(), Err(RangeError::Overflow { start: 0x1000, len: !0 }));
+    assert!(Range::covering(at(!0 - 10), 0x20, page_size).is_err());
+
+    let (head, tail) = range.split_at(0x1000).unwrap();
+    assert_eq!((head.len, tail.start), (0x1000, at(0x2000)));
+    assert_eq!(range.split_at(0x2001), Err(RangeError::OutOfBounds { offset: 0x2001, len: 0x2000 }));
+    assert_eq!(head.intersect(&range), Some(head));
+    assert_eq!(head.intersect(&tail), None);
+
+    let (handle, _) = Builder::new().create().unwrap();
+    match handle.register(Range { start: at(0x1001), len: 0x1000 }, ::REGISTER_MISSING) {
+        Err(::UffdError::InvalidRange(RangeError::UnalignedStart { .. })) => {}
+        other => panic!("unexpected result {:?}", other)
+    }
+}
+
+#[test]
+fn monitor_pool_resolves_concurrent_faults() {
+    use std::thread;
+
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().non_block(true).create().unwrap();
+    let monitor = ::MonitorBuilder::new().threads(4).spawn(handle, move |addr: usize, page: &mut [u8]| {
+        for b in page.iter_mut() {
+            *b = (addr / page_size) as u8;
+        }
+        Ok(())
+    }).unwrap();
+    let region = monitor.handle().create_region(32 * page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    let mem = region.as_ptr() as usize;
+    // Every thread walks all the pages, so most faults are raised by several threads at once
+    let threads: Vec<_> = (0..8).map(|_| thread::spawn(move || {
+        for i in 0..32 {
+            let addr = mem + i * page_size;
+            assert_eq!(unsafe { *(addr as *const u8) }, (addr / page_size) as u8);
+        }
+    })).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    drop(region);
+    monitor.shutdown().unwrap();
+}
+
+#[test]
+fn handle_survives_transfer() {
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use transfer::{recv_handle, send_handle};
+
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().create().unwrap();
+    let region = handle.create_region(2 * page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    let (a, b) = UnixStream::pair().unwrap();
+    send_handle(&a, &handle, &[region.info()]).unwrap();
+    let (received, regions) = recv_handle(&b).unwrap();
+    assert_eq!(regions, vec![region.info()]);
+    assert!(received.as_raw_fd() != handle.as_raw_fd());
+
+    let addr = regions[0].start + page_size;
+    let toucher = thread::spawn(move || unsafe { *(addr as *const u8) });
+    match received.read_message().unwrap() {
+        ::Message::Pagefault(fault) => {
+            assert_eq!(fault.address as usize, addr);
+            received.zeropage(::Range { start: addr as *mut u8, len: page_size }, ::ZeropageMode::empty()).unwrap();
+        }
+        other => panic!("unexpected message {:?}", other)
+    }
+    assert_eq!(toucher.join().unwrap(), 0);
+}
+
+#[test]
+fn write_protect_blocks_writers_until_lifted() {
+    use std::thread;
+
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().features(::FEATURE_PAGEFAULT_FLAG_WP).create().unwrap();
+    let mut region = handle.create_region(2 * page_size, ::Backing::Anonymous, ::REGISTER_WP).unwrap();
+    for b in region.as_mut_slice().iter_mut() {
+        *b = 1;
+    }
+    handle.write_protect(region.range(), true, ::WriteprotectMode::empty()).unwrap();
+    let addr = region.as_ptr() as usize + page_size + 5;
+    let writer = thread::spawn(move || unsafe { *(addr as *mut u8) = 2 });
+    match handle.read_message().unwrap() {
+        ::Message::Pagefault(fault) => {
+            assert_eq!(fault.address as usize, addr & !(page_size - 1));
+            assert!(fault.pagefault_flags().contains(::PAGEFAULT_FLAG_WP | ::PAGEFAULT_FLAG_WRITE));
+            // The write has not gone through while the page is protected
+            assert_eq!(region.as_slice()[page_size + 5], 1);
+            let page = ::Range { start: fault.address as *mut u8, len: page_size };
+            handle.write_protect(page, false, ::WriteprotectMode::empty()).unwrap();
+        }
+        other => panic!("unexpected message {:?}", other)
+    }
+    writer.join().unwrap();
+    assert_eq!(region.as_slice()[page_size + 5], 2);
+}
+
+#[test]
+fn continue_range_maps_page_cache() {
+    use std::os::unix::fs::FileExt;
+    use std::thread;
+
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().minor_shmem(true).create().unwrap();
+    let region = handle.create_region(2 * page_size, ::Backing::Memfd, ::REGISTER_MINOR).unwrap();
+    // Writing through the file fills the page cache without mapping the pages into the region
+    region.file().unwrap().write_at(&vec![7; 2 * page_size], 0).unwrap();
+    let addr = region.as_ptr() as usize + page_size;
+    let reader = thread::spawn(move || unsafe { *(addr as *const u8) });
+    match handle.read_message().unwrap() {
+        ::Message::Pagefault(fault) => {
+            assert_eq!(fault.address as usize, addr);
+            assert!(fault.pagefault_flags().contains(::PAGEFAULT_FLAG_MINOR));
+            let page = ::Range { start: addr as *mut u8, len: page_size };
+            handle.continue_range(page, ::ContinueMode::empty()).unwrap();
+            match handle.continue_range(page, ::ContinueMode::empty()) {
+                Err(::UffdError::PageExists) => {}
+                other => panic!("unexpected result {:?}", other)
+            }
+        }
+        other => panic!("unexpected message {:?}", other)
+    }
+    assert_eq!(reader.join().unwrap(), 7);
+}
+
+#[test]
+fn move_pages_stops_at_source_hole() {
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().move_pages(true).create().unwrap();
+    let region = handle.create_region(3 * page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    // The middle page of the staging buffer is never touched and stays unpopulated
+    let mut staging = Mapping::new(3 * page_size);
+    staging.as_mut_slice()[0] = 1;
+    staging.as_mut_slice()[2 * page_size] = 3;
+    let ret = handle.move_pages(region.as_ptr(), staging.start, 3 * page_size as u64, ::MoveMode::empty());
+    match ret {
+        Err(::MoveError { moved, error: ::UffdError::MappingChanged }) => assert_eq!(moved, page_size as u64),
+        other => panic!("unexpected result {:?}", other)
+    }
+    assert_eq!(region.as_slice()[0], 1);
+    // The moved page is gone from the staging buffer, the one after the hole is still there
+    assert_eq!(staging.as_mut_slice()[0], 0);
+    assert_eq!(staging.as_mut_slice()[2 * page_size], 3);
+}
+
+#[test]
+fn poisoned_page_raises_sigbus() {
+    use libc;
+
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().poison(true).create().unwrap();
+    let region = handle.create_region(2 * page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    let page = ::Range { start: unsafe { region.as_ptr().add(page_size) }, len: page_size };
+    handle.poison(page, ::PoisonMode::empty()).unwrap();
+    let first = ::Range { start: region.as_ptr(), len: page_size };
+    handle.zeropage(first, ::ZeropageMode::empty()).unwrap();
+    match handle.poison(first, ::PoisonMode::empty()) {
+        Err(::UffdError::PageExists) => {}
+        other => panic!("unexpected result {:?}", other)
+    }
+    // The poison marker is inherited, so the access can be made from a child without killing the tests
+    let addr = page.start as usize;
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        unsafe {
+            let _ = ::std::ptr::read_volatile(addr as *const u8);
+            libc::_exit(0);
+        }
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGBUS);
+}
+
+#[test]
+fn sigbus_handler_resolves_and_chains() {
+    use libc;
+    use std::os::raw::{c_int, c_void};
+    use std::sync::Arc;
+    use std::{mem, panic, ptr};
+
+    extern "C" fn previous(_: c_int, _: *mut libc::siginfo_t, _: *mut c_void) {
+        unsafe { libc::_exit(42); }
+    }
+
+    let page_size = ::PageSize::system().bytes();
+    // The handler chains to whatever was installed before the first call to handle_sigbus(), so this
+    // part runs in a child that installs its own handler first
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        let _ = panic::catch_unwind(|| unsafe {
+            let mut sa: libc::sigaction = mem::zeroed();
+            sa.sa_sigaction = previous as *const () as usize;
+            sa.sa_flags = libc::SA_SIGINFO;
+            libc::sigaction(libc::SIGBUS, &sa, ptr::null_mut());
+            let (handle, _) = Builder::new().sigbus(true).create().unwrap();
+            let handle = Arc::new(handle);
+            let region = handle.create_region(page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+            let _routed = ::handle_sigbus(handle.clone(), region.range(), |_: &Handle, _| false).unwrap();
+            ptr::read_volatile(region.as_ptr());
+        });
+        unsafe { libc::_exit(1); }
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 42);
+
+    let (handle, _) = Builder::new().sigbus(true).create().unwrap();
+    let handle = Arc::new(handle);
+    let region = handle.create_region(2 * page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    let mut fill = vec![9u8; page_size];
+    let src = fill.as_mut_ptr() as usize;
+    let routed = ::handle_sigbus(handle.clone(), region.range(), move |handle: &Handle, addr: *mut u8| {
+        let page = (addr as usize & !(page_size - 1)) as *mut u8;
+        handle.copy(page, src as *mut u8, page_size as u64, ::CopyMode::empty()).is_ok()
+    }).unwrap();
+    assert_eq!(region.as_slice()[page_size + 1], 9);
+    drop(routed);
+    drop(fill);
+}
+
+#[test]
+fn undrained_fork_messages_close_their_handles() {
+    use libc;
+    use std::mem;
+
+    let mut buf = ::MessageBuffer::new(4);
+    let mut fds = Vec::new();
+    for i in 0..3 {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let fork = ::ForkMessage { _event: raw_interface::defines::UFFD_EVENT_FORK as u8, _res1: 0, _res2: 0, _res3: 0,
+                                   ufd: fd as u32, _pad1: 0, _pad2: 0, _pad3: 0 };
+        buf.msgs[i] = unsafe { mem::transmute::<::ForkMessage, raw_interface::defines::uffd_msg>(fork) };
+        fds.push(fd);
+    }
+    buf.len = 3;
+    let is_open = |fd: RawFd| unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0;
+    match buf.drain().next() {
+        Some(::Message::Fork(_)) => {}
+        other => panic!("unexpected message {:?}", other)
+    }
+    assert!(buf.is_empty());
+    assert!(fds.iter().all(|&fd| !is_open(fd)));
+}
+
+#[test]
+fn monitor_forgets_exited_children() {
+    use libc;
+    use std::time::{Duration, Instant};
+    use std::{ptr, thread};
+
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().event_fork(true).create().unwrap();
+    let monitor = ::MonitorBuilder::new().follow_fork(true).spawn(handle, |_: usize, page: &mut [u8]| {
+        for b in page.iter_mut() {
+            *b = 5;
+        }
+        Ok(())
+    }).unwrap();
+    let region = monitor.handle().create_region(3 * page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    assert_eq!(region.as_slice()[0], 5);
+    // The child's fault is only resolved if the monitor follows it
+    let addr = region.as_ptr() as usize + page_size;
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        unsafe { libc::_exit(if ptr::read_volatile(addr as *const u8) == 5 { 0 } else { 1 }); }
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    // Wake the monitor up so it notices the child is gone
+    assert_eq!(region.as_slice()[2 * page_size], 5);
+    let start = Instant::now();
+    while monitor.children() != 0 {
+        assert!(start.elapsed() < Duration::from_secs(5), "exited child is still served");
+        thread::sleep(Duration::from_millis(1));
+    }
+    drop(region);
+    monitor.shutdown().unwrap();
+}
+
+#[test]
+fn monitor_resolves_fault_across_fork() {
+    use libc;
+    use std::time::Duration;
+    use std::{ptr, thread};
+
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().event_fork(true).create().unwrap();
+    // The process forks while the page is being filled, so the copy fails until the fork event is read.
+    // The source must not allocate: glibc holds the allocator locks until then
+    let monitor = ::MonitorBuilder::new().follow_fork(true).spawn(handle, |_: usize, page: &mut [u8]| {
+        thread::sleep(Duration::from_millis(200));
+        for b in page.iter_mut() {
+            *b = 7;
+        }
+        Ok(())
+    }).unwrap();
+    let region = monitor.handle().create_region(page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    let addr = region.as_ptr() as usize;
+    let reader = thread::spawn(move || unsafe { ptr::read_volatile(addr as *const u8) });
+    thread::sleep(Duration::from_millis(50));
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        unsafe { libc::_exit(0); }
+    }
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert_eq!(reader.join().unwrap(), 7);
+    drop(region);
+    monitor.shutdown().unwrap();
+}
+
+#[test]
+fn snapshot_catches_writes_to_unpopulated_pages() {
+    let page_size = ::PageSize::system().bytes();
+    let mut mapping = Mapping::new(4 * page_size);
+    let (handle, _) = Builder::new().features(::FEATURE_PAGEFAULT_FLAG_WP).create().unwrap();
+    let snapshot = ::Snapshot::take(handle, ::Range { start: mapping.start, len: mapping.len }).unwrap();
+    mapping.as_mut_slice()[2 * page_size] = 1;
+    let mut copy = vec![1; page_size];
+    snapshot.read(2 * page_size, &mut copy);
+    assert!(copy.iter().all(|&b| b == 0));
+    assert_eq!(snapshot.saved_pages(), 1);
+    snapshot.release().unwrap();
+}
+
+#[test]
+fn pagefault_reports_thread_id() {
+    use libc;
+    use std::sync::mpsc;
+    use std::thread;
+
+    let page_size = ::PageSize::system().bytes();
+    let (handle, _) = Builder::new().thread_id(true).create().unwrap();
+    let region = handle.create_region(page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    let addr = region.as_ptr() as usize;
+    let (tx, rx) = mpsc::channel();
+    let reader = thread::spawn(move || {
+        tx.send(unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t).unwrap();
+        unsafe { *(addr as *const u8) }
+    });
+    let tid = rx.recv().unwrap();
+    match handle.read_message().unwrap() {
+        ::Message::Pagefault(fault) => {
+            assert_eq!(fault.thread_id(), Some(tid));
+            handle.zeropage(::Range { start: fault.address as *mut u8, len: page_size }, ::ZeropageMode::empty()).unwrap();
+        }
+        other => panic!("unexpected message {:?}", other)
+    }
+    assert_eq!(reader.join().unwrap(), 0);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn async_handle_reads_fault_on_current_thread_runtime() {
+    use std::thread;
+    use tokio::runtime;
+
+    let page_size = ::PageSize::system().bytes();
+    let rt = runtime::Builder::new_current_thread().enable_io().build().unwrap();
+    let _guard = rt.enter();
+    let (handle, _) = Builder::new().create().unwrap();
+    let handle = ::AsyncHandle::new(handle).unwrap();
+    let region = handle.get_ref().create_region(page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+    let addr = region.as_ptr() as usize;
+    let reader = thread::spawn(move || unsafe { *(addr as *const u8) });
+    match rt.block_on(handle.read_message()).unwrap() {
+        ::Message::Pagefault(fault) => {
+            let page = ::Range { start: fault.address as *mut u8, len: page_size };
+            handle.get_ref().zeropage(page, ::ZeropageMode::empty()).unwrap();
+        }
+        other => panic!("unexpected message {:?}", other)
+    }
+    assert_eq!(reader.join().unwrap(), 0);
+}
+
+#[cfg(any(feature = "mio08", feature = "mio1"))]
+macro_rules! mio_source_test {
+    ($name:ident, $mio:ident) => {
+        #[test]
+        fn $name() {
+            use std::thread;
+            use std::time::Duration;
+            use $mio::{Events, Interest, Poll, Token};
+
+            let page_size = ::PageSize::system().bytes();
+            let (mut handle, _) = Builder::new().non_block(true).create().unwrap();
+            let mut poll = Poll::new().unwrap();
+            poll.registry().register(&mut handle, Token(7), Interest::READABLE).unwrap();
+            let region = handle.create_region(page_size, ::Backing::Anonymous, ::REGISTER_MISSING).unwrap();
+            let addr = region.as_ptr() as usize;
+            let reader = thread::spawn(move || unsafe { *(addr as *const u8) });
+            let mut events = Events::with_capacity(4);
+            poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+            assert!(events.iter().any(|e| e.token() == Token(7) && e.is_readable()));
+            match handle.read_message().unwrap() {
+                ::Message::Pagefault(fault) => {
+                    let page = ::Range { start: fault.address as *mut u8, len: page_size };
+                    handle.zeropage(page, ::ZeropageMode::empty()).unwrap();
+                }
+                other => panic!("unexpected message {:?}", other)
+            }
+            assert_eq!(reader.join().unwrap(), 0);
+            drop(region);
+            poll.registry().deregister(&mut handle).unwrap();
+        }
+    }
+}
+
+#[cfg(feature = "mio08")]
+mio_source_test!(mio08_source_reports_faults, mio08);
+#[cfg(feature = "mio1")]
+mio_source_test!(mio1_source_reports_faults, mio1);
@@ -0,0 +1,163 @@
+//! Passing a `Handle` to another process over a Unix socket.
+//!
+//! This lets a separate, possibly more privileged or crash-isolated, process serve the faults of an
+//! application: the application creates the handle, registers its regions and sends the handle along with
+//! a description of the regions. Fault addresses read from the received handle, and the destination
+//! addresses of `copy()` and the other ioctls, refer to the address space of the application, while
+//! source buffers are read from the address space of the serving process.
+//!
+//! The handle travels as an `SCM_RIGHTS` control message attached to an 8 byte header holding a magic
+//! number and the number of regions, followed by each region as four little-endian `u64`s.
+
+use libc;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use {Handle, Ioctls, RegisterMode};
+
+const MAGIC: u32 = 0x5546_4644;
+const REGION_SIZE: usize = 32;
+
+/// The maximum number of regions that can be sent along with a handle. A peer announcing more is
+/// rejected before anything is allocated for them.
+pub const MAX_REGIONS: usize = 4096;
+
+/// The description of a registered region sent along with a handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionInfo {
+    /// The start address of the region in the address space of the process that registered it.
+    pub start: usize,
+    pub len: usize,
+    /// The mode the region was registered with.
+    pub mode: RegisterMode,
+    /// The operations available on the region, as returned by `register()`.
+    pub ioctls: Ioctls,
+}
+
+/// Send `handle` and the description of its `regions` over `stream`.
+///
+/// The handle stays open in the sending process. Faults keep being queued on it while it is in transit,
+/// and are read by whichever process reads from the handle first. Fails with `InvalidInput` if there are
+/// more than `MAX_REGIONS` regions.
+pub fn send_handle(stream: &UnixStream, handle: &Handle, regions: &[RegionInfo]) -> Result<(), Error> {
+    if regions.len() > MAX_REGIONS {
+        return Err(Error::new(ErrorKind::InvalidInput, "too many regions"));
+    }
+    let mut header = [0u8; 8];
+    header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..].copy_from_slice(&(regions.len() as u32).to_le_bytes());
+    let mut iov = libc::iovec { iov_base: header.as_mut_ptr() as *mut libc::c_void, iov_len: header.len() };
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, handle.as_raw_fd());
+    }
+    let sent = loop {
+        let n = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if n >= 0 {
+            break n as usize;
+        }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+    // The descriptor is attached to the first byte, the rest can follow as plain data
+    let mut stream = stream;
+    stream.write_all(&header[sent..])?;
+    let mut body = Vec::with_capacity(regions.len() * REGION_SIZE);
+    for region in regions {
+        body.extend_from_slice(&(region.start as u64).to_le_bytes());
+        body.extend_from_slice(&(region.len as u64).to_le_bytes());
+        body.extend_from_slice(&region.mode.bits().to_le_bytes());
+        body.extend_from_slice(&region.ioctls.bits().to_le_bytes());
+    }
+    stream.write_all(&body)
+}
+
+/// Receive a handle and the description of its regions sent with `send_handle()`.
+///
+/// Fails with `InvalidData` if the message is not a handle sent with `send_handle()` or announces more
+/// than `MAX_REGIONS` regions.
+pub fn recv_handle(stream: &UnixStream) -> Result<(Handle, Vec<RegionInfo>), Error> {
+    let mut header = [0u8; 8];
+    let mut iov = libc::iovec { iov_base: header.as_mut_ptr() as *mut libc::c_void, iov_len: header.len() };
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let received = loop {
+        let n = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if n >= 0 {
+            break n as usize;
+        }
+        let e = Error::last_os_error();
+        if e.kind() != ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+    // Take ownership of every descriptor received, so the unexpected ones are closed
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / mem::size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(Handle::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if received == 0 {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed before a handle was received"));
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 || fds.len() != 1 {
+        return Err(Error::new(ErrorKind::InvalidData, "expected exactly one userfaultfd descriptor"));
+    }
+    let handle = fds.pop().unwrap();
+
+    let mut stream = stream;
+    stream.read_exact(&mut header[received..])?;
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&header[..4]);
+    if u32::from_le_bytes(word) != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a userfaultfd handle message"));
+    }
+    word.copy_from_slice(&header[4..]);
+    let count = u32::from_le_bytes(word) as usize;
+    if count > MAX_REGIONS {
+        return Err(Error::new(ErrorKind::InvalidData, "too many regions"));
+    }
+    let mut body = vec![0u8; count * REGION_SIZE];
+    stream.read_exact(&mut body)?;
+    let regions = body.chunks(REGION_SIZE).map(|region| {
+        let field = |i: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&region[i * 8..(i + 1) * 8]);
+            u64::from_le_bytes(bytes)
+        };
+        RegionInfo {
+            start: field(0) as usize,
+            len: field(1) as usize,
+            mode: RegisterMode::from_bits_truncate(field(2)),
+            ioctls: Ioctls::from_bits_truncate(field(3)),
+        }
+    }).collect();
+    Ok((handle, regions))
+}
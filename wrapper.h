@@ -1,10 +1,101 @@
 #include <linux/userfaultfd.h>
+#include <linux/fs.h>
 #include <sys/ioctl.h>
 #include <fcntl.h>
 
+// Definitions from newer kernels, so the bindings can still be generated against older headers.
+#ifndef UFFDIO_MOVE
+#define _UFFDIO_MOVE (0x05)
+struct uffdio_move {
+	__u64 dst;
+	__u64 src;
+	__u64 len;
+#define UFFDIO_MOVE_MODE_DONTWAKE ((__u64)1<<0)
+#define UFFDIO_MOVE_MODE_ALLOW_SRC_HOLES ((__u64)1<<1)
+	__u64 mode;
+	__s64 move;
+};
+#define UFFDIO_MOVE _IOWR(UFFDIO, _UFFDIO_MOVE, struct uffdio_move)
+#endif
+
+#ifndef UFFD_FEATURE_MOVE
+#define UFFD_FEATURE_MOVE (1<<16)
+#endif
+
+#ifndef UFFDIO_POISON
+#define _UFFDIO_POISON (0x08)
+struct uffdio_poison {
+	struct uffdio_range range;
+#define UFFDIO_POISON_MODE_DONTWAKE ((__u64)1<<0)
+	__u64 mode;
+	__s64 updated;
+};
+#define UFFDIO_POISON _IOWR(UFFDIO, _UFFDIO_POISON, struct uffdio_poison)
+#endif
+
+#ifndef UFFD_FEATURE_POISON
+#define UFFD_FEATURE_POISON (1<<14)
+#endif
+
+#ifndef UFFD_FEATURE_WP_UNPOPULATED
+#define UFFD_FEATURE_WP_UNPOPULATED (1<<13)
+#endif
+
+#ifndef UFFD_FEATURE_WP_ASYNC
+#define UFFD_FEATURE_WP_ASYNC (1<<15)
+#endif
+
+#ifndef UFFD_USER_MODE_ONLY
+#define UFFD_USER_MODE_ONLY 1
+#endif
+
+#ifndef PAGEMAP_SCAN
+#define PAGE_IS_WPALLOWED (1 << 0)
+#define PAGE_IS_WRITTEN (1 << 1)
+#define PAGE_IS_FILE (1 << 2)
+#define PAGE_IS_PRESENT (1 << 3)
+#define PAGE_IS_SWAPPED (1 << 4)
+#define PAGE_IS_PFNZERO (1 << 5)
+#define PAGE_IS_HUGE (1 << 6)
+#define PAGE_IS_SOFT_DIRTY (1 << 7)
+struct page_region {
+	__u64 start;
+	__u64 end;
+	__u64 categories;
+};
+#define PM_SCAN_WP_MATCHING (1 << 0)
+#define PM_SCAN_CHECK_WPASYNC (1 << 1)
+struct pm_scan_arg {
+	__u64 size;
+	__u64 flags;
+	__u64 start;
+	__u64 end;
+	__u64 walk_end;
+	__u64 vec;
+	__u64 vec_len;
+	__u64 max_pages;
+	__u64 category_inverted;
+	__u64 category_mask;
+	__u64 category_anyof_mask;
+	__u64 return_mask;
+};
+#define PAGEMAP_SCAN _IOWR('f', 16, struct pm_scan_arg)
+#endif
+
+#ifndef USERFAULTFD_IOC_NEW
+#define USERFAULTFD_IOC 0xAA
+#define USERFAULTFD_IOC_NEW _IO(USERFAULTFD_IOC, 0x00)
+#endif
+
 const long int _T_UFFD_API = UFFD_API;
 #undef UFFD_API
 
+const long int _T_USERFAULTFD_IOC_NEW = USERFAULTFD_IOC_NEW;
+#undef USERFAULTFD_IOC_NEW
+
+const long int _T_PAGEMAP_SCAN = PAGEMAP_SCAN;
+#undef PAGEMAP_SCAN
+
 const long int _T_UFFDIO_API = UFFDIO_API;
 #undef UFFDIO_API
 
@@ -23,18 +114,54 @@ const long int _T_UFFDIO_COPY = UFFDIO_COPY;
 const long int _T_UFFDIO_ZEROPAGE = UFFDIO_ZEROPAGE;
 #undef UFFDIO_ZEROPAGE
 
+const long int _T_UFFDIO_WRITEPROTECT = UFFDIO_WRITEPROTECT;
+#undef UFFDIO_WRITEPROTECT
+
+const long int _T_UFFDIO_CONTINUE = UFFDIO_CONTINUE;
+#undef UFFDIO_CONTINUE
+
+const long int _T_UFFDIO_MOVE = UFFDIO_MOVE;
+#undef UFFDIO_MOVE
+
+const long int _T_UFFDIO_POISON = UFFDIO_POISON;
+#undef UFFDIO_POISON
+
 const long int _T_UFFDIO_REGISTER_MODE_MISSING = UFFDIO_REGISTER_MODE_MISSING;
 #undef UFFDIO_REGISTER_MODE_MISSING
 
 const long int _T_UFFDIO_REGISTER_MODE_WP = UFFDIO_REGISTER_MODE_WP;
 #undef UFFDIO_REGISTER_MODE_WP
 
+const long int _T_UFFDIO_REGISTER_MODE_MINOR = UFFDIO_REGISTER_MODE_MINOR;
+#undef UFFDIO_REGISTER_MODE_MINOR
+
 const long int _T_UFFDIO_COPY_MODE_DONTWAKE = UFFDIO_COPY_MODE_DONTWAKE;
 #undef UFFDIO_COPY_MODE_DONTWAKE
 
 const long int _T_UFFDIO_ZEROPAGE_MODE_DONTWAKE = UFFDIO_ZEROPAGE_MODE_DONTWAKE;
 #undef UFFDIO_ZEROPAGE_MODE_DONTWAKE
 
+const long int _T_UFFDIO_COPY_MODE_WP = UFFDIO_COPY_MODE_WP;
+#undef UFFDIO_COPY_MODE_WP
+
+const long int _T_UFFDIO_WRITEPROTECT_MODE_WP = UFFDIO_WRITEPROTECT_MODE_WP;
+#undef UFFDIO_WRITEPROTECT_MODE_WP
+
+const long int _T_UFFDIO_WRITEPROTECT_MODE_DONTWAKE = UFFDIO_WRITEPROTECT_MODE_DONTWAKE;
+#undef UFFDIO_WRITEPROTECT_MODE_DONTWAKE
+
+const long int _T_UFFDIO_CONTINUE_MODE_DONTWAKE = UFFDIO_CONTINUE_MODE_DONTWAKE;
+#undef UFFDIO_CONTINUE_MODE_DONTWAKE
+
+const long int _T_UFFDIO_MOVE_MODE_DONTWAKE = UFFDIO_MOVE_MODE_DONTWAKE;
+#undef UFFDIO_MOVE_MODE_DONTWAKE
+
+const long int _T_UFFDIO_MOVE_MODE_ALLOW_SRC_HOLES = UFFDIO_MOVE_MODE_ALLOW_SRC_HOLES;
+#undef UFFDIO_MOVE_MODE_ALLOW_SRC_HOLES
+
+const long int _T_UFFDIO_POISON_MODE_DONTWAKE = UFFDIO_POISON_MODE_DONTWAKE;
+#undef UFFDIO_POISON_MODE_DONTWAKE
+
 const long int _T_UFFD_API_IOCTLS = UFFD_API_IOCTLS;
 #undef UFFD_API_IOCTLS
 